@@ -0,0 +1,169 @@
+use std::{
+    collections::HashSet,
+    io::{Cursor, Write},
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use serde::Serialize;
+use serenity::model::prelude::UserId;
+use zip::{
+    CompressionMethod,
+    write::FileOptions,
+    ZipWriter,
+};
+use crate::structs::*;
+
+// describes what's in a dump archive, saved next to the audio as manifest.json
+#[derive(Serialize)]
+pub struct Manifest {
+    pub created_at: DateTime<Utc>,
+    pub format: &'static str,
+    pub pauses: bool,
+    pub tracks: Vec<ManifestTrack>,
+    pub failed: Vec<String>, // the tracks that could not be encoded
+}
+
+#[derive(Serialize)]
+pub struct ManifestTrack {
+    pub name: String,
+    pub file: String,
+    pub user_id: Option<UserId>, // none for the mix
+    pub ssrc: Option<u32>,
+    pub duration: f64, // seconds
+    pub start: Option<DateTime<Utc>>, // only known when the pauses were kept whole
+    pub end: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>, // who is on each channel of a multitrack file
+}
+
+impl ManifestTrack {
+    pub fn new(track: &Track, file: String, uncut: bool, created_at: DateTime<Utc>, channels: Vec<String>) -> Self {
+        let duration = track.duration();
+        Self {
+            name: track.name.clone(),
+            file,
+            user_id: track.user_id,
+            ssrc: track.ssrc,
+            duration: duration.as_secs_f64(),
+            start: if uncut { ChronoDuration::from_std(duration).ok().map(|duration| created_at - duration) } else { None },
+            end: created_at,
+            channels,
+        }
+    }
+}
+
+// sums the tracks lined up by their ends, which is when the dump was made; tracks in another format than the first are left out
+pub fn mix(tracks: &[Track]) -> Option<Track> {
+    let format = tracks.first()?.format;
+    let tracks: Vec<&Track> = tracks.iter().filter(|track| track.format == format).collect();
+    let sources = tracks.iter().flat_map(|track| track.sources.iter().cloned()).collect();
+    let length = tracks.iter().map(|track| track.samples.len()).max()?;
+    let mut mixed = vec![0i32; length];
+    for track in tracks {
+        let offset = length - track.samples.len();
+        for (mixed, sample) in mixed[offset..].iter_mut().zip(track.samples.iter()) {
+            *mixed += *sample as i32;
+        }
+    }
+    Some(Track {
+        name: "mix".to_string(),
+        user_id: None,
+        ssrc: None,
+        format,
+        samples: mixed.into_iter()
+            .map(|sample| sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .collect(),
+        segments: Vec::new(),
+        sources,
+    })
+}
+
+// one channel per track, each downmixed to mono and lined up by their ends like the mix
+pub fn multitrack(tracks: &[Track]) -> Option<Track> {
+    let rate = tracks.first()?.format.rate;
+    let tracks: Vec<&Track> = tracks.iter().filter(|track| track.format.rate == rate).collect();
+    let sources = tracks.iter().flat_map(|track| track.sources.iter().cloned()).collect();
+    let monos: Vec<Vec<i16>> = tracks.iter().map(|track| {
+        let channels = track.format.channels as usize;
+        track.samples.chunks(channels)
+            .map(|frame| (frame.iter().map(|sample| *sample as i32).sum::<i32>() / frame.len() as i32) as i16)
+            .collect()
+    }).collect();
+    let length = monos.iter().map(Vec::len).max()?;
+    let mut samples = vec![0i16; length * monos.len()];
+    for (channel, mono) in monos.iter().enumerate() {
+        let offset = length - mono.len();
+        for (frame, sample) in mono.iter().enumerate() {
+            samples[(offset + frame) * monos.len() + channel] = *sample;
+        }
+    }
+    Some(Track {
+        name: "multitrack".to_string(),
+        user_id: None,
+        ssrc: None,
+        format: AudioFormat { rate, channels: monos.len() as u16 },
+        samples,
+        segments: Vec::new(),
+        sources,
+    })
+}
+
+// file names that are already taken get a number, two members can share a name
+pub fn unique_name(taken: &mut HashSet<String>, stem: &str, extension: &str) -> String {
+    let stem = safe_stem(stem);
+    let mut name = format!("{}.{}", stem, extension);
+    let mut n = 1;
+    while !taken.insert(name.clone()) {
+        n += 1;
+        name = format!("{}-{}.{}", stem, n, extension);
+    }
+    name
+}
+
+// member names are picked by the members, they can't be allowed to make a path out of the zip or file name
+fn safe_stem(stem: &str) -> String {
+    let stem: String = stem.chars()
+        .map(|c| if c.is_control() || "/\\:*?\"<>|".contains(c) { '_' } else { c })
+        .collect();
+    // leading dots would hide the file or climb out with ..
+    let stem = stem.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if stem.is_empty() {
+        "audio".to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+// lossless audio still shrinks when deflated, the rest is stored as it is
+pub fn zip(files: &[(Vec<u8>, String)], manifest: &Manifest, deflate_audio: bool) -> Result<Vec<u8>, String> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let audio_method = if deflate_audio { CompressionMethod::Deflated } else { CompressionMethod::Stored };
+    for (bytes, name) in files {
+        zip.start_file(&name[..], FileOptions::default().compression_method(audio_method))
+            .map_err(|why| why.to_string())?;
+        zip.write_all(bytes).map_err(|why| why.to_string())?;
+    }
+    let manifest = serde_json::to_vec_pretty(manifest).expect("manifests are always serializable");
+    zip.start_file("manifest.json", FileOptions::default().compression_method(CompressionMethod::Deflated))
+        .map_err(|why| why.to_string())?;
+    zip.write_all(&manifest).map_err(|why| why.to_string())?;
+    Ok(zip.finish().map_err(|why| why.to_string())?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_inside_the_archive() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_name(&mut taken, "../../etc/passwd", "ogg"), "_.._etc_passwd.ogg");
+        assert_eq!(unique_name(&mut taken, "..\\windows", "ogg"), "_windows.ogg");
+        assert_eq!(unique_name(&mut taken, "..", "ogg"), "audio.ogg");
+        assert_eq!(unique_name(&mut taken, " .hidden", "ogg"), "hidden.ogg");
+        assert_eq!(unique_name(&mut taken, "what?\n", "ogg"), "what__.ogg");
+        assert_eq!(unique_name(&mut taken, "Kévin", "ogg"), "Kévin.ogg");
+        // two names that end up the same still get a number
+        assert_eq!(unique_name(&mut taken, "a/b", "ogg"), "a_b.ogg");
+        assert_eq!(unique_name(&mut taken, "a\\b", "ogg"), "a_b-2.ogg");
+    }
+}
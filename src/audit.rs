@@ -0,0 +1,83 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use serenity::model::prelude::{ChannelId, GuildId, UserId};
+use crate::structs::{AuditLog, CommandOption, Reply};
+
+// one line of the audit log, the log is only ever appended to
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub command: String,
+    pub options: Value,
+    pub exported: Vec<UserId>, // the users whose audio was sent in chat
+    #[serde(default)]
+    pub denied: bool, // whether the permission rules stopped it
+}
+
+pub async fn record(response: &impl Reply, exported: Vec<UserId>) {
+    write(response, exported, false).await;
+}
+
+// an attempt the permission rules didn't let through
+pub async fn record_denied(response: &impl Reply) {
+    write(response, Vec::new(), true).await;
+}
+
+async fn write(response: &impl Reply, exported: Vec<UserId>, denied: bool) {
+    let audit_log = response.typemap().read().await.get::<AuditLog>().expect("Typemap incomplete").clone();
+    let data = response.data();
+    let entry = AuditEntry {
+        time: Utc::now(),
+        guild_id: response.guild_id(),
+        channel_id: response.channel_id(),
+        user_id: response.member(),
+        command: data.name.clone(),
+        options: options_to_json(&data.options),
+        exported,
+        denied,
+    };
+    let line = serde_json::to_string(&entry).expect("audit entries are always serializable");
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(why) = result {
+        eprintln!("Error writing the audit log: {:?}", why);
+    }
+}
+
+// the latest entries of a guild, oldest first
+pub fn query(audit_log: &str, guild_id: GuildId, user_id: Option<UserId>, command: Option<&str>, limit: usize) -> Vec<AuditEntry> {
+    let contents = fs::read_to_string(audit_log).unwrap_or_default();
+    let mut entries: Vec<AuditEntry> = contents.lines()
+        .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+        .filter(|entry| entry.guild_id == guild_id)
+        .filter(|entry| user_id.is_none_or(|user_id| entry.user_id == user_id))
+        .filter(|entry| command.is_none_or(|command| entry.command == command))
+        .collect();
+    let skip = entries.len().saturating_sub(limit);
+    entries.drain(..skip);
+    entries
+}
+
+// subcommands become nested objects
+fn options_to_json(options: &[CommandOption]) -> Value {
+    let mut map = Map::new();
+    for option in options {
+        let value = match &option.value {
+            Some(value) => value.clone(),
+            None => options_to_json(&option.options),
+        };
+        map.insert(option.name.clone(), value);
+    }
+    Value::Object(map)
+}
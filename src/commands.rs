@@ -1,270 +1,1306 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
+    ops::Range,
     process::Stdio,
-    sync::Arc
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::{Duration, Instant},
 };
-use serenity::{
-    client::Context,
-    model::{
-        misc::Mentionable,
-        prelude::UserId,
-    },
-};
-use songbird::{
-    CoreEvent,
+use serenity::model::{
+    misc::Mentionable,
+    prelude::{MessageId, RoleId, UserId},
 };
 use tokio::{
     io::AsyncWriteExt,
     process::Command,
-    sync::Mutex,
+    sync::{mpsc, watch, Mutex},
     task,
 };
-use crate::structs::*;
-use serenity::model::guild::Guild;
+use chrono::Utc;
+
+// how often the live meter is updated, discord doesn't like much faster edits
+const LIVE_INTERVAL: Duration = Duration::from_secs(2);
+// the most an embed's description can hold
+const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+
+// numbers the running dumps
+static NEXT_DUMP: AtomicU64 = AtomicU64::new(0);
+use crate::{
+    archive,
+    audit,
+    processing::Processing,
+    snapshot,
+    stats::{self, Session},
+    structs::*,
+    timeline::{self, TimelineFormat},
+};
 use serenity::model::id::ChannelId;
-use serde_json::value::Value::Bool;
+use serde_json::value::Value::{Bool, Number, String as JsonString};
 
-pub async fn join(ctx: &Context, response: Response) {
-    let (guild, _) = response.guild(ctx).await;
-    let user_channel = guild.voice_states.get(&response.member()).and_then(|vs| vs.channel_id);
+pub async fn join(response: impl Reply) {
+    let user_channel = response.voice_channel(response.member()).await;
     match user_channel {
         None => {
-            response.edit(ctx, "Error: you first need to be in a voice channel").await;
+            response.edit("Error: you first need to be in a voice channel").await;
         }
         Some(user_channel_id) => {
-            match move_to(ctx, guild, user_channel_id).await {
+            match move_to(&response, user_channel_id).await {
                 Ok(()) => {
-                    response.follow_up(ctx, &format!("Joined {}", user_channel_id.mention())[..]).await;
+                    response.follow_up(&format!("Joined {}", user_channel_id.mention())[..]).await;
                 },
                 Err(()) => {
-                    response.follow_up(ctx, &format!("Error: couldn't join {}", user_channel_id.mention())[..]).await;
+                    response.follow_up(&format!("Error: couldn't join {}", user_channel_id.mention())[..]).await;
                 },
             }
         }
     }
 }
 
-pub async fn leave(ctx: &Context, response: Response) {
-    let (guild, guild_id) = response.guild(ctx).await;
-    if let Some(current_state) = guild
-        .voice_states
-        .get(&ctx.cache.current_user_id().await)
-    {
-        if let Some(channel_id) = guild
-            .voice_states
-            .get(&response.member())
-            .and_then(|vs| vs.channel_id)
-            .filter(|user_channel_id| *user_channel_id == current_state.channel_id.unwrap())
+pub async fn leave(response: impl Reply) {
+    let guild_id = response.guild_id();
+    if let Some(current_channel_id) = response.voice_channel(response.bot_id().await).await {
+        if let Some(channel_id) = response.voice_channel(response.member()).await
+            .filter(|user_channel_id| *user_channel_id == current_channel_id)
         {
-            let manager = songbird::get(ctx).await
-                .expect("Songbird Voice client placed in at initialisation.").clone();
-            if let Some(call) = manager.get(guild_id) {
-                if let Ok(_) = call.lock().await.leave().await {
+            match response.leave_voice().await {
+                Some(Ok(())) => {
                     //response.delete(ctx);
-                    response.follow_up(ctx, &format!("Left {}", channel_id.mention())[..]).await;
-                } else {
-                    response.edit(ctx, &format!("Error: Could not leave {}", channel_id.mention())[..]).await;
-                }
-            } else {
-                response.edit(ctx, "Error: The bot is not in a call").await;
+                    response.follow_up(&format!("Left {}", channel_id.mention())[..]).await;
+                },
+                Some(Err(())) => {
+                    response.edit(&format!("Error: Could not leave {}", channel_id.mention())[..]).await;
+                },
+                None => {
+                    response.edit("Error: The bot is not in a call").await;
+                },
             }
             // to prevent poison errors, whenever the bot leaves it deletes the buffer for the server
             {
-                let data_read = ctx.data.read().await;
+                let data_read = response.typemap().read().await;
                 let _ = data_read.get::<JoinFlag>().expect("Typemap incomplete").clone().lock().await.insert(guild_id);
                 let buffers_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
                 buffers_lock.write().await.remove(&guild_id);
             };
         } else {
-            response.edit(ctx, "Error: You have to be in the same channel as the bot to remove it").await;
+            response.edit("Error: You have to be in the same channel as the bot to remove it").await;
         }
     } else {
-        response.edit(ctx, "Error: The bot is not in a voice channel").await;
+        response.edit("Error: The bot is not in a voice channel").await;
     }
 }
 
-pub async fn dump(ctx: &Context, response: Response) {
-    let (guild, guild_id) = response.guild(ctx).await;
-    let members = guild.members;
-    let data_read = ctx.data.read().await;
+pub async fn dump(response: impl Reply) {
+    let guild_id = response.guild_id();
+    let data_read = response.typemap().read().await;
     let lobbies_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
-    if let Some(lobby_lock) = lobbies_lock.read().await.get(&guild_id).clone() {
+    let encoding_queue = data_read.get::<EncodingQueue>().expect("Typemap incomplete").clone();
+    let dump_cancels = data_read.get::<DumpCancels>().expect("Typemap incomplete").clone();
+    let dump_defaults = *data_read.get::<DumpDefaults>().expect("Typemap incomplete");
+    drop(data_read);
+    // cloned out so the other guilds aren't locked out while this one encodes and uploads
+    let lobby_lock = lobbies_lock.read().await.get(&guild_id).cloned();
+    if let Some(lobby_lock) = lobby_lock {
+        let mut options = DumpOptions::from(&response.data().options, &dump_defaults);
+        // the tracks can only be lined up when nothing was cut out of them
+        if options.mix && !options.uncut() {
+            audit::record(&response, Vec::new()).await;
+            response.edit("Error: mix needs every pause kept whole, it can't be used with pauses:false, max_pause or DISCORD_MAX_SILENCE_GAP").await;
+            return;
+        }
+        if options.timeline.is_some() && !options.uncut() {
+            audit::record(&response, Vec::new()).await;
+            response.edit("Error: timeline needs every pause kept whole, it can't be used with pauses:false, max_pause or DISCORD_MAX_SILENCE_GAP").await;
+            return;
+        }
+        response.edit("Extracting the audio...").await;
+        // every dump has its own channel, so cancelling one leaves the others running
+        let (cancel, cancelled) = watch::channel(false);
+        let dump_number = NEXT_DUMP.fetch_add(1, Ordering::SeqCst);
+        let running = RunningDump {
+            guild_id,
+            user_id: response.member(),
+            message_id: response.add_cancel_reaction().await,
+            cancel,
+        };
+        dump_cancels.lock().await.insert(dump_number, running);
         let lobby = lobby_lock.0.lock().await;
         let ssrc_map = lobby_lock.1.lock().await;
-        let encoded_buffers = Arc::new(Mutex::new(Vec::<(Vec<u8>, String)>::new()));
-        let mut encoding_threads = Vec::new();
-        let output_format = output_format();
-        for (id, audio_state_buffer) in lobby.iter() {
-            if let Some(user_id) = ssrc_map.get(&id) {
-                if let Some(member) = &members.get(user_id) {
-                    let options = &response.data().as_ref().unwrap().options;
-                    let mut insert_pauses = true;
-                    for option in options {
-                        match &option.name[..] {
-                            "pauses" => {
-                                if let Some(Bool(val)) = option.value {
-                                    insert_pauses = val;
-                                }
-                            },
-                            _ => {}
-                        }
-                    }
+        let restored = lobby_lock.2.lock().await;
+        // those who haven't talked since a restart only have what was restored
+        let buffers: Vec<(UserId, Option<u32>, &Buffer)> = lobby.iter()
+            .filter_map(|(ssrc, buffer)| Some((*ssrc_map.get(ssrc)?, Some(*ssrc), buffer)))
+            .chain(restored.iter().map(|(user_id, buffer)| (*user_id, None, buffer)))
+            .collect();
+        let mut tracks = Vec::new();
+        for (user_id, ssrc, audio_state_buffer) in buffers {
+            if let Some(name) = response.member_name(user_id).await {
+                let (samples, segments) = if options.pauses {
+                    audio_state_buffer.pop_uncompressed(options.max_pause, dump_defaults.max_silence)
+                } else {
+                    audio_state_buffer.pop_compressed()
+                };
+                tracks.push(Track {
+                    name,
+                    user_id: Some(user_id),
+                    sources: vec![user_id],
+                    ssrc,
+                    format: audio_state_buffer.format(),
+                    samples,
+                    segments,
+                });
+            }
+        };
+        // the lobby isn't needed anymore, recording goes on while encoding
+        drop(restored);
+        drop(ssrc_map);
+        drop(lobby);
+        if !options.processing.is_empty() {
+            response.edit("Processing the audio...").await;
+            let processing = options.processing;
+            tracks = task::spawn_blocking(move || {
+                for track in tracks.iter_mut() {
+                    processing.apply(track);
+                }
+                tracks
+            }).await.expect("could not process the audio");
+        }
+        let created_at = Utc::now();
+        let timeline = options.timeline.map(|format| (timeline::render(&timeline::segments(&tracks), format), format));
+        // who ends up on which channel, in the same order multitrack puts them
+        let mut channels = Vec::new();
+        if options.multitrack {
+            if let Some(multitrack) = archive::multitrack(&tracks) {
+                channels = tracks.iter()
+                    .filter(|track| track.format.rate == multitrack.format.rate)
+                    .map(|track| track.name.clone())
+                    .collect();
+                // downmixing would put everyone back together
+                options.channels = None;
+                // flac can't hold more than 8 channels
+                if !options.format.lossless() || (options.format == OutputFormat::Flac && channels.len() > 8) {
+                    options.format = if channels.len() > 8 { OutputFormat::Wav } else { OutputFormat::Flac };
+                }
+                tracks = vec![multitrack];
+            }
+        } else if options.mix && tracks.len() > 1 {
+            if let Some(mix) = archive::mix(&tracks) {
+                tracks.push(mix);
+            }
+        }
+        let tracks: Vec<Arc<Track>> = tracks.into_iter().map(Arc::new).collect();
+
+        let (finished, mut finished_receiver) = mpsc::unbounded_channel();
+        for (index, track) in tracks.iter().enumerate() {
+            let track = track.clone();
+            let options = options.clone();
+            let encoding_queue = encoding_queue.clone();
+            let finished = finished.clone();
+            let mut cancelled = cancelled.clone();
+            task::spawn(async move {
+                // cancelling drops the encoder, which kills ffmpeg
+                tokio::select! {
+                    encoded = async {
+                        let _slot = encoding_queue.acquire(guild_id).await;
+                        encode(&track.samples, track.format, &options).await
+                    } => {
+                        let _ = finished.send((index, encoded));
+                    },
+                    _ = cancelled.wait_for(|cancelled| *cancelled) => {},
+                }
+            });
+        }
+        drop(finished);
+
+        let mut encoded_buffers = vec![None; tracks.len()];
+        let mut failures = Vec::new();
+        let mut done = 0;
+        response.edit(&format!("Encoding 0/{}... ({} or /cancel to stop)", tracks.len(), CANCEL_REACTION)[..]).await;
+        // a crashed or cancelled encoding task drops its sender, which ends this early
+        while let Some((index, encoded)) = finished_receiver.recv().await {
+            let name = &tracks[index].name;
+            match encoded {
+                Ok(encoded) => encoded_buffers[index] = Some(encoded),
+                Err(why) => {
+                    eprintln!("Error encoding {}'s audio: {}", name, why);
+                    failures.push(format!("{}: {}", name, why.lines().last().unwrap_or("unknown error")));
+                },
+            }
+            done += 1;
+            if done < tracks.len() {
+                response.edit(&format!("Encoding {}/{}... ({} or /cancel to stop)", done, tracks.len(), CANCEL_REACTION)[..]).await;
+            }
+        }
+        dump_cancels.lock().await.remove(&dump_number);
+        // cancelled or failed dumps are still logged, without anyone's audio
+        if *cancelled.borrow() {
+            audit::record(&response, Vec::new()).await;
+            response.edit("Cancelled.").await;
+            return;
+        }
+        if done < tracks.len() {
+            failures.push(format!("{} more: the encoder crashed", tracks.len() - done));
+        }
 
-                    let buffer: Vec<i16>;
-                    if insert_pauses {
-                        buffer = audio_state_buffer.pop_uncompressed()
-                    } else {
-                        buffer = audio_state_buffer.pop_compressed()
+        let extension = options.format.extension();
+        let mut taken = HashSet::new();
+        let mut files = Vec::new();
+        let mut manifest_tracks = Vec::new();
+        let mut exported: Vec<UserId> = Vec::new();
+        for (track, encoded) in tracks.iter().zip(encoded_buffers) {
+            if let Some(encoded) = encoded {
+                for user_id in &track.sources {
+                    if !exported.contains(user_id) {
+                        exported.push(*user_id);
                     }
-                    let name = member.user.name.clone();
-                    let encoded_buffers_clone = encoded_buffers.clone();
-                    let output_format = output_format.clone();
-                    encoding_threads.push(
-                        task::spawn(async move {
-                            let mut child = Command::new("ffmpeg")
-                                .args(
-                                    &[
-                                        "-f", "s16be", // format in input
-                                        "-ac", "2", // audio channels in input
-                                        "-ar", "48k", // audio rate
-                                        "-i", "-", // input takes a pipe
-                                        "-f", &output_format[..], // output format
-                                        "-b:a", "96k", // output rate
-                                        "-ac", "2", // output audio channels
-                                        "-" // output takes a pipe
-                                    ])
-                                .stdin(Stdio::piped())
-                                .stdout(Stdio::piped())
-                                .stderr(Stdio::null())
-                                .spawn().expect("could not spawn encoder");
-
-                            let samples = get_bytes(&buffer);
-                            let mut stdin = child.stdin.take().expect("failed to open stdin");
-                            task::spawn(async move {
-                                stdin.write_all(&samples[..]).await.unwrap();
-                            });
-                            let encoded = child.wait_with_output().await.unwrap().stdout;
-                            encoded_buffers_clone.lock().await.push((encoded, format!("{}.{}", name, output_format)));
-                        }));
                 }
+                let file = archive::unique_name(&mut taken, &track.name, extension);
+                let track_channels = if track.user_id.is_none() { channels.clone() } else { Vec::new() };
+                manifest_tracks.push(archive::ManifestTrack::new(track, file.clone(), options.uncut(), created_at, track_channels));
+                files.push((encoded, file));
             }
+        }
+        // the timeline only goes along with audio
+        if !files.is_empty() {
+            if let Some((timeline, format)) = timeline {
+                let file = archive::unique_name(&mut taken, "timeline", format.name());
+                files.push((timeline.into_bytes(), file));
+            }
+        }
+        if options.archive && !files.is_empty() {
+            let manifest = archive::Manifest {
+                created_at,
+                format: options.format.name(),
+                pauses: options.pauses,
+                tracks: manifest_tracks,
+                failed: failures.clone(),
+            };
+            match archive::zip(&files, &manifest, options.format.lossless()) {
+                Ok(zipped) => files = vec![(zipped, format!("dump-{}.zip", created_at.format("%Y%m%d-%H%M%S")))],
+                Err(why) => {
+                    eprintln!("Error zipping the dump: {}", why);
+                    audit::record(&response, Vec::new()).await;
+                    response.edit("Error: could not put the files in an archive").await;
+                    return;
+                },
+            }
+        }
+
+        // whatever was encoded is still sent when some of it failed
+        if files.is_empty() {
+            audit::record(&response, Vec::new()).await;
+            if failures.is_empty() {
+                response.edit("Done!").await;
+            } else {
+                response.edit(&format!("Error: could not encode the audio of\n{}", failures.join("\n"))[..]).await;
+            }
+        } else {
+            let status = if failures.is_empty() {
+                "Done!".to_string()
+            } else {
+                format!("Done, but could not encode the audio of\n{}", failures.join("\n"))
+            };
+            response.edit(&format!("Uploading {} files...", files.len())[..]).await;
+            if response.follow_up_files(&files).await.is_ok() {
+                audit::record(&response, exported).await;
+                response.edit(&status[..]).await;
+            } else {
+                audit::record(&response, Vec::new()).await;
+                response.edit("Error: could not upload the files").await;
+            }
+        }
+    } else {
+        audit::record(&response, Vec::new()).await;
+        response.edit("Error: The bot is not in a voice channel").await;
+    };
+}
+
+// runs ffmpeg on the samples, failing with what it printed when it doesn't succeed
+async fn encode(buffer: &[i16], format: AudioFormat, options: &DumpOptions) -> Result<Vec<u8>, String> {
+    let output_format = options.format;
+    let channels = format.channels.to_string();
+    let rate = format.rate.to_string();
+    let bitrate = format!("{}k", options.bitrate);
+    // resampling and downmixing are left to ffmpeg
+    let output_rate = options.sample_rate.unwrap_or(format.rate).to_string();
+    let output_channels = options.channels.unwrap_or(format.channels).to_string();
+    let mut encoder = Command::new("ffmpeg");
+    encoder.args(
+            [
+                "-f", "s16be", // format in input
+                "-ac", &channels[..], // audio channels in input
+                "-ar", &rate[..], // audio rate
+                "-i", "-", // input takes a pipe
+                "-f", output_format.muxer(), // output format
+                "-c:a", output_format.codec(), // output codec
+                "-ar", &output_rate[..], // output audio rate
+                "-ac", &output_channels[..], // output audio channels
+            ])
+        .args(output_format.extra_args());
+    if !output_format.lossless() {
+        encoder.args(["-b:a", &bitrate[..]]); // output bitrate
+    }
+    let mut child = encoder
+        .arg("-") // output takes a pipe
+        .kill_on_drop(true)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn().map_err(|why| format!("could not start ffmpeg: {}", why))?;
+
+    let samples = get_bytes(buffer);
+    let mut stdin = child.stdin.take().expect("failed to open stdin");
+    // a failed write means ffmpeg quit early, its exit status says why
+    task::spawn(async move {
+        let _ = stdin.write_all(&samples[..]).await;
+    });
+    let output = child.wait_with_output().await.map_err(|why| format!("ffmpeg did not finish: {}", why))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffmpeg failed ({}):\n{}", output.status, stderr.trim_end()));
+    }
+    if output.stdout.is_empty() {
+        return Err("ffmpeg produced no audio".to_string());
+    }
+    Ok(output.stdout)
+}
+
+// what /dump was asked for, the encoder settings default to the environment
+#[derive(Clone)]
+struct DumpOptions {
+    format: OutputFormat,
+    archive: bool, // one zip with every file and a manifest
+    mix: bool, // an extra track with everyone together
+    multitrack: bool, // a single file with everyone on their own channel, instead of a file each
+    timeline: Option<TimelineFormat>, // a file with who spoke when
+    processing: Processing, // runs on every speaker's audio before the mix
+    pauses: bool,
+    max_pause: Option<Duration>,
+    bitrate: u64, // kbps
+    sample_rate: Option<u32>, // the buffer's when unset
+    channels: Option<u16>, // the buffer's when unset
+}
+
+impl DumpOptions {
+    fn from(options: &[CommandOption], defaults: &DumpDefaults) -> Self {
+        let mut dump_options = DumpOptions {
+            format: defaults.format,
+            archive: false,
+            mix: false,
+            multitrack: false,
+            timeline: None,
+            processing: Processing::default(),
+            pauses: true,
+            max_pause: defaults.max_pause,
+            bitrate: defaults.bitrate,
+            sample_rate: defaults.sample_rate,
+            channels: defaults.channels,
         };
+        for option in options {
+            match (&option.name[..], &option.value) {
+                ("format", Some(JsonString(val))) => if let Some(format) = OutputFormat::from_name(val) {
+                    dump_options.format = format;
+                },
+                ("archive", Some(Bool(val))) => dump_options.archive = *val,
+                ("mix", Some(Bool(val))) => dump_options.mix = *val,
+                ("multitrack", Some(Bool(val))) => dump_options.multitrack = *val,
+                ("timeline", Some(JsonString(val))) => dump_options.timeline = TimelineFormat::from_name(val),
+                ("normalize", Some(Bool(val))) => dump_options.processing.normalize = *val,
+                ("high_pass", Some(Bool(val))) => dump_options.processing.high_pass = *val,
+                ("gate", Some(Bool(val))) => dump_options.processing.gate = *val,
+                ("denoise", Some(Bool(val))) => dump_options.processing.denoise = *val,
+                ("pauses", Some(Bool(val))) => dump_options.pauses = *val,
+                ("max_pause", Some(Number(val))) => if let Some(millis) = val.as_u64() {
+                    dump_options.max_pause = Some(Duration::from_millis(millis));
+                },
+                ("bitrate", Some(Number(val))) => if let Some(kbps) = val.as_u64().filter(|kbps| *kbps > 0) {
+                    dump_options.bitrate = kbps;
+                },
+                ("sample_rate", Some(Number(val))) => if let Some(rate) = val.as_u64().filter(|rate| *rate > 0) {
+                    dump_options.sample_rate = Some(rate as u32);
+                },
+                ("channels", Some(Number(val))) => if let Some(channels) = val.as_u64().filter(|channels| *channels > 0) {
+                    dump_options.channels = Some(channels as u16);
+                },
+                _ => {},
+            }
+        }
+        // the channels only line up when every pause is kept whole
+        if dump_options.multitrack {
+            dump_options.pauses = true;
+            dump_options.max_pause = None;
+        }
+        dump_options
+    }
 
-        for handle in encoding_threads.drain(..) {
-            handle.await.unwrap();
+    // whether the audio keeps its real timing, which max_pause and DISCORD_MAX_SILENCE_GAP both cut into
+    fn uncut(&self) -> bool {
+        self.pauses && self.max_pause.is_none()
+    }
+}
+
+// stops the dumps running in the guild, what they encoded so far is thrown away
+pub async fn cancel(response: impl Reply) {
+    let guild_id = response.guild_id();
+    let dump_cancels = response.typemap().read().await.get::<DumpCancels>().expect("Typemap incomplete").clone();
+    let mut cancelled = 0;
+    for running in dump_cancels.lock().await.values().filter(|running| running.guild_id == guild_id) {
+        // sending fails when the dump stopped listening
+        if running.cancel.send(true).is_ok() {
+            cancelled += 1;
         }
+    }
+    match cancelled {
+        0 => response.edit("Error: there is no dump to cancel").await,
+        1 => response.edit("Cancelling the dump...").await,
+        _ => response.edit(&format!("Cancelling {} dumps...", cancelled)[..]).await,
+    }
+}
+
+// a reaction on the progress reply of a dump, which only cancels it when it comes from whoever started it
+pub async fn cancel_by_reaction(dump_cancels: &Mutex<HashMap<u64, RunningDump>>, message_id: MessageId, user_id: UserId) -> bool {
+    dump_cancels.lock().await.values()
+        .find(|running| running.message_id == Some(message_id) && running.user_id == user_id)
+        .is_some_and(|running| running.cancel.send(true).is_ok())
+}
 
-        response.edit(ctx, "Done!").await;
-        response.follow_up_files(ctx, &*encoded_buffers.clone().lock().await).await;
+// talk time, turns, longest turn and interruptions of everyone in the buffers
+pub async fn stats(response: impl Reply) {
+    let guild_id = response.guild_id();
+    let data_read = response.typemap().read().await;
+    let lobbies_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
+    let lobby_lock = match lobbies_lock.read().await.get(&guild_id).cloned() {
+        Some(lobby_lock) => lobby_lock,
+        None => {
+            response.edit("Error: The bot is not in a voice channel").await;
+            return;
+        }
+    };
+    let session = response.data().options.iter()
+        .any(|option| option.name == "over" && option.value == Some(JsonString("session".to_string())));
+    let (window, speakers) = if session {
+        let session = lobby_lock.3.lock().await;
+        (session.started.elapsed(), session.stats(Instant::now()))
+    } else {
+        let speech: Vec<(UserId, Vec<Range<Instant>>)> = {
+            let lobby = lobby_lock.0.lock().await;
+            let ssrc_map = lobby_lock.1.lock().await;
+            let restored = lobby_lock.2.lock().await;
+            lobby.iter()
+                .filter_map(|(ssrc, buffer)| Some((*ssrc_map.get(ssrc)?, buffer.speech())))
+                .chain(restored.iter().map(|(user_id, buffer)| (*user_id, buffer.speech())))
+                .collect()
+        };
+        let window = speech.iter()
+            .filter_map(|(_, speech)| speech.first())
+            .map(|segment| segment.start.elapsed())
+            .max();
+        match window {
+            Some(window) => (window, stats::compute(&speech)),
+            None => (Duration::ZERO, Vec::new()),
+        }
+    };
+    if speakers.is_empty() {
+        response.edit("Nobody has talked yet").await;
+        return;
+    }
+    let mut lines = if session {
+        vec![format!("Since the bot joined, {} ago:", stats::format_duration(window))]
+    } else {
+        vec![format!("Over the last {} of buffered audio:", stats::format_duration(window))]
     };
+    for speaker in speakers {
+        let name = response.member_name(speaker.user_id).await
+            .unwrap_or_else(|| speaker.user_id.mention().to_string());
+        lines.push(format!("**{}**: talked for {}, {} turns, longest {}, {} interruptions",
+                           name, stats::format_duration(speaker.talk_time), speaker.turns,
+                           stats::format_duration(speaker.longest_turn), speaker.interruptions));
+    }
+    response.edit(&lines.join("\n")[..]).await;
 }
 
-pub async fn clear(ctx: &Context, response: Response) {
-    let (_, guild_id) = response.guild(ctx).await;
+// keeps editing the reply with who is talking and how loud, until the time is up or it's stopped
+pub async fn live(response: impl Reply) {
+    let guild_id = response.guild_id();
+    let data_read = response.typemap().read().await;
+    let lobbies_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
+    let live_meters = data_read.get::<LiveMeters>().expect("Typemap incomplete").clone();
+    let shutdown_flag = data_read.get::<ShutdownFlag>().expect("Typemap incomplete").clone();
+    drop(data_read);
+    let mut seconds = 60;
+    let mut stop = false;
+    for option in &response.data().options {
+        match (&option.name[..], &option.value) {
+            ("seconds", Some(Number(val))) => if let Some(val) = val.as_u64() {
+                seconds = val.clamp(1, 600);
+            },
+            ("stop", Some(Bool(val))) => stop = *val,
+            _ => {},
+        }
+    }
+    // a new meter replaces the old one
+    if let Some(running) = live_meters.lock().await.remove(&guild_id) {
+        let _ = running.send(true);
+        if stop {
+            response.edit("Stopped the live meter").await;
+            return;
+        }
+    }
+    if stop {
+        response.edit("Error: there is no live meter to stop").await;
+        return;
+    }
+    let lobby_lock = match lobbies_lock.read().await.get(&guild_id).cloned() {
+        Some(lobby_lock) => lobby_lock,
+        None => {
+            response.edit("Error: The bot is not in a voice channel").await;
+            return;
+        }
+    };
+    let (stopper, mut stopped) = watch::channel(false);
+    live_meters.lock().await.insert(guild_id, stopper);
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let mut interval = tokio::time::interval(LIVE_INTERVAL);
+    while Instant::now() < deadline {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = stopped.wait_for(|stopped| *stopped) => break,
+        }
+        // in case it started after the shutdown stopped the other meters
+        if shutdown_flag.0.load(Ordering::SeqCst) {
+            break;
+        }
+        let levels: Vec<(UserId, f64)> = {
+            let lobby = lobby_lock.0.lock().await;
+            let ssrc_map = lobby_lock.1.lock().await;
+            lobby.iter()
+                .filter_map(|(ssrc, buffer)| Some((*ssrc_map.get(ssrc)?, buffer.level()?)))
+                .collect()
+        };
+        let mut lines = vec![format!("Live, for {} more seconds:", deadline.saturating_duration_since(Instant::now()).as_secs())];
+        if levels.is_empty() {
+            lines.push("Nobody is talking".to_string());
+        }
+        for (user_id, level) in levels {
+            let name = response.member_name(user_id).await
+                .unwrap_or_else(|| user_id.mention().to_string());
+            lines.push(format!("`{}` **{}**", level_bar(level), name));
+        }
+        response.edit(&lines.join("\n")[..]).await;
+    }
     {
-        let data_read = ctx.data.read().await;
+        // only forget the meter if it's still this one
+        let mut live_meters = live_meters.lock().await;
+        if live_meters.get(&guild_id).is_some_and(|running| running.subscribe().same_channel(&stopped)) {
+            live_meters.remove(&guild_id);
+        }
+    }
+    response.edit("The live meter stopped").await;
+}
+
+// -60 dBFS and below is empty, 0 dBFS is full
+fn level_bar(level: f64) -> String {
+    let db = 20.0 * level.max(1e-6).log10();
+    let filled = (((db + 60.0) / 6.0).round().clamp(0.0, 10.0)) as usize;
+    format!("{}{}", "█".repeat(filled), "░".repeat(10 - filled))
+}
+
+pub async fn clear(response: impl Reply) {
+    let guild_id = response.guild_id();
+    {
+        let data_read = response.typemap().read().await;
         let lobbies_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
-        let lobby_lock = lobbies_lock.read().await.get(&guild_id).expect("could not acquire a read lock on the data").clone();
+        let lobby_lock = match lobbies_lock.read().await.get(&guild_id).cloned() {
+            Some(lobby_lock) => lobby_lock,
+            None => {
+                response.edit("Error: The bot is not in a voice channel").await;
+                return;
+            }
+        };
         let buffer = &mut lobby_lock.0.lock().await;
         buffer.clear();
+        lobby_lock.2.lock().await.clear();
     }
     //response.delete(ctx);
-    response.follow_up(ctx, "The buffer has been cleared. No need to thank me").await;
+    response.follow_up("The buffer has been cleared. No need to thank me").await;
 }
 
 // make the bot follow the user who calls this
-pub async fn follow(ctx: &Context, response: Response) {
+pub async fn follow(response: impl Reply) {
     let user_id = response.member();
-    let (guild, guild_id) = response.guild(ctx).await;
+    let guild_id = response.guild_id();
     {
-        let data_read = ctx.data.read().await;
+        let data_read = response.typemap().read().await;
         let follow_map = data_read.get::<FollowFlag>().expect("Typemap incomplete").clone();
         follow_map.lock().await.insert(guild_id, user_id);
     };
-    response.follow_up(ctx, &format!("The bot will now follow {}", user_id.mention())[..]).await;
+    response.follow_up(&format!("The bot will now follow {}", user_id.mention())[..]).await;
 
-    let user_channel = guild.voice_states.get(&user_id).and_then(|vs| vs.channel_id);
-    if let Some(user_channel_id) = user_channel {
-        let _ = move_to(ctx, guild, user_channel_id).await;
+    if let Some(user_channel_id) = response.voice_channel(user_id).await {
+        let _ = move_to(&response, user_channel_id).await;
     }
 }
 
-pub async fn unfollow(ctx: &Context, response: Response) {
+pub async fn unfollow(response: impl Reply) {
     let user_id = response.member();
-    let (_, guild_id) = response.guild(ctx).await;
+    let guild_id = response.guild_id();
     {
-        let data_read = ctx.data.read().await;
+        let data_read = response.typemap().read().await;
         let follow_map = data_read.get::<FollowFlag>().expect("Typemap incomplete").clone();
         let mut follow_map_lock = follow_map.lock().await;
         match follow_map_lock.get(&guild_id) {
             Some(mapped_user_id) if *mapped_user_id == user_id => {
                 let _ = follow_map_lock.remove(&guild_id);
-                response.follow_up(ctx, &format!("The bot has stopped following {}.", user_id.mention())[..]).await;
+                response.follow_up(&format!("The bot has stopped following {}.", user_id.mention())[..]).await;
             },
             _ => {
-                response.follow_up(ctx, &format!("I don't even know who {} is.", user_id.mention())[..]).await;
+                response.follow_up(&format!("I don't even know who {} is.", user_id.mention())[..]).await;
             },
         }
     };
 }
 
-pub async fn move_to(ctx: &Context, guild: Guild, target_channel_id: ChannelId) -> Result<(),()> {
-    let guild_id = guild.id;
-    if let Some(current_channel_id) = guild.voice_states.get(&ctx.cache.current_user_id().await).and_then(|vs| vs.channel_id) {
-        if current_channel_id == target_channel_id {
-            return Ok(());
+// shows the latest entries of the audit log to admins
+pub async fn audit(response: impl Reply) {
+    let member_permissions = response.member_permissions();
+    if !member_permissions.administrator() && !member_permissions.manage_guild() {
+        response.edit("Error: you need the Manage Server permission to read the audit log").await;
+        return;
+    }
+    let mut user_id = None;
+    let mut command = None;
+    let mut limit = 10;
+    for option in &response.data().options {
+        match (&option.name[..], &option.value) {
+            ("user", Some(JsonString(id))) => user_id = id.parse().ok().map(UserId),
+            ("command", Some(JsonString(name))) => command = Some(name.clone()),
+            ("limit", Some(Number(n))) => limit = n.as_u64().unwrap_or(10).clamp(1, 25) as usize,
+            _ => {}
+        }
+    }
+    let audit_log = response.typemap().read().await.get::<AuditLog>().expect("Typemap incomplete").clone();
+    let entries = audit::query(&audit_log, response.guild_id(), user_id, command.as_deref(), limit);
+    if entries.is_empty() {
+        response.edit("The audit log is empty").await;
+        return;
+    }
+    // an edit, so that mentions don't ping anyone
+    let lines = entries.iter().map(|entry| {
+        let mut line = format!("`{}` {} {} /{} in {}",
+                               entry.time.format("%Y-%m-%d %H:%M:%S"),
+                               entry.user_id.mention(),
+                               if entry.denied { "was denied" } else { "used" },
+                               entry.command,
+                               entry.channel_id.mention());
+        if entry.options.as_object().is_some_and(|options| !options.is_empty()) {
+            line.push_str(&format!(" with `{}`", entry.options));
+        }
+        if !entry.exported.is_empty() {
+            let exported: Vec<String> = entry.exported.iter().map(|user_id| user_id.mention().to_string()).collect();
+            line.push_str(&format!(", exporting {}", exported.join(", ")));
+        }
+        line
+    }).collect::<Vec<_>>();
+    response.edit(&fit_description(&lines)[..]).await;
+}
+
+// keeps the newest lines that fit in an embed, and says how many older ones didn't
+fn fit_description(lines: &[String]) -> String {
+    // room for the note about the older lines, however many there are
+    let room = EMBED_DESCRIPTION_LIMIT - format!("{} older entries left out\n", lines.len()).len();
+    let mut kept = 0;
+    let mut length = 0;
+    for line in lines.iter().rev() {
+        length += line.chars().count() + 1;
+        if length > room {
+            break;
+        }
+        kept += 1;
+    }
+    let newest = lines[lines.len() - kept..].join("\n");
+    if kept == lines.len() {
+        newest
+    } else {
+        format!("{} older entries left out\n{}", lines.len() - kept, newest)
+    }
+}
+
+// lets admins restrict who can use the other commands
+pub async fn permissions(response: impl Reply) {
+    let member_permissions = response.member_permissions();
+    if !member_permissions.administrator() && !member_permissions.manage_guild() {
+        response.edit("Error: you need the Manage Server permission to change who can use the bot").await;
+        return;
+    }
+    let subcommand = match response.data().options.first() {
+        Some(subcommand) => subcommand,
+        None => return,
+    };
+    let mut command_name = None;
+    let mut role_id = None;
+    let mut permission = None;
+    for option in &subcommand.options {
+        match (&option.name[..], &option.value) {
+            ("command", Some(JsonString(name))) => command_name = Some(name.clone()),
+            ("role", Some(JsonString(id))) => role_id = id.parse().ok().map(RoleId),
+            ("permission", Some(JsonString(name))) => permission = PERMISSION_NAMES.iter()
+                .find(|(permission_name, _)| permission_name == name),
+            _ => {}
+        }
+    }
+
+    let guild_id = response.guild_id();
+    let data_read = response.typemap().read().await;
+    let rules_lock = data_read.get::<PermissionRules>().expect("Typemap incomplete").clone();
+    // replies are edits so that role mentions don't ping anyone
+    if subcommand.name == "list" {
+        let rules = rules_lock.read().await;
+        let mut lines = rules.get(&guild_id).into_iter().flatten().map(|(name, rule)| {
+            let mut allowed: Vec<String> = rule.roles.iter().map(|role_id| role_id.mention().to_string()).collect();
+            if let Some((permission_name, _)) = PERMISSION_NAMES.iter()
+                .find(|(_, permission)| permission.bits() == rule.permissions) {
+                allowed.push(format!("`{}`", permission_name));
+            }
+            format!("/{}: {}", name, allowed.join(", "))
+        }).collect::<Vec<_>>();
+        lines.sort();
+        if lines.is_empty() {
+            response.edit("Everyone can use every command").await;
+        } else {
+            response.edit(&lines.join("\n")[..]).await;
         }
+        return;
     }
-    let data_read = ctx.data.read().await;
+    let mut rules = rules_lock.write().await;
+    let guild_rules = rules.entry(guild_id).or_default();
+    let reply = match (&subcommand.name[..], command_name, role_id, permission) {
+        ("allow", Some(name), Some(role_id), _) => {
+            guild_rules.entry(name.clone()).or_default().roles.insert(role_id);
+            format!("Members with {} can now use /{}", role_id.mention(), name)
+        },
+        ("disallow", Some(name), Some(role_id), _) => match guild_rules.get_mut(&name) {
+            Some(rule) => {
+                rule.roles.remove(&role_id);
+                // the empty rule is dropped below, and without a rule nobody is restricted
+                if rule.is_empty() {
+                    format!("Everyone can now use /{}, {} was the last thing it was restricted to", name, role_id.mention())
+                } else {
+                    format!("Members with {} can no longer use /{} because of that role", role_id.mention(), name)
+                }
+            },
+            None => format!("Everyone can already use /{}", name),
+        },
+        ("require", Some(name), _, Some((permission_name, permission))) => {
+            guild_rules.entry(name.clone()).or_default().permissions = permission.bits();
+            format!("Members with `{}` can now use /{}", permission_name, name)
+        },
+        ("reset", Some(name), _, _) => {
+            guild_rules.remove(&name);
+            format!("Everyone can now use /{}", name)
+        },
+        _ => "Error: invalid options".to_string(),
+    };
+    guild_rules.retain(|_, rule| !rule.is_empty());
+    if guild_rules.is_empty() {
+        rules.remove(&guild_id);
+    }
+    save_permission_rules(&rules);
+    response.edit(&reply[..]).await;
+}
+
+pub async fn move_to(server: &impl Server, target_channel_id: ChannelId) -> Result<(),()> {
+    let guild_id = server.guild_id();
+    if server.voice_channel(server.bot_id().await).await == Some(target_channel_id) {
+        return Ok(());
+    }
+    let data_read = server.typemap().read().await;
     let join_flag = data_read.get::<JoinFlag>().expect("Typemap incomplete").clone();
     let _ = join_flag.lock().await.insert(guild_id);
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
-
-    let (handler_lock, conn_result) = manager.join(guild_id, target_channel_id).await;
 
-    return if let Ok(_) = conn_result {
-        let audio_buffer: HashMap<u32, Buffer> = HashMap::new();
-        let ssrc_map: HashMap<u32, UserId> = HashMap::new();
-        let lobby = Arc::new((Mutex::new(audio_buffer), Mutex::new(ssrc_map)));
+    if server.join_voice(target_channel_id).await.is_ok() {
+        // picks up where the bot left off before a restart, if it saved a snapshot
+        let restored = snapshot::restore(guild_id).unwrap_or_default();
+        let lobby = Arc::new((Mutex::new(HashMap::new()), Mutex::new(HashMap::new()), Mutex::new(restored), Mutex::new(Session::new())));
         let buffers_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
         buffers_lock.write().await.insert(guild_id, lobby.clone());
-
-        // NOTE: this skips listening for the actual connection result.
-        let mut handler = handler_lock.lock().await;
-
-        handler.add_global_event(
-            CoreEvent::VoicePacket.into(),
-            Receiver::new(lobby.clone()),
-        );
-        handler.add_global_event(
-            CoreEvent::SpeakingStateUpdate.into(),
-            Receiver::new(lobby.clone()),
-        );
-        handler.add_global_event(
-            CoreEvent::SpeakingUpdate.into(),
-            Receiver::new(lobby.clone()),
-        );
-        handler.add_global_event(
-            CoreEvent::ClientDisconnect.into(),
-            Receiver::new(lobby.clone()),
-        );
+        server.listen(lobby).await;
+        if announce_enabled() {
+            announce(server, target_channel_id).await;
+        }
         Ok(())
     } else {
         Err(())
     }
 }
 
-fn get_bytes(origin: &Vec<i16>) -> Vec<u8> {
+// lets the people in the channel know that they are being recorded
+async fn announce(server: &impl Server, channel_id: ChannelId) {
+    let bot_id = server.bot_id().await;
+    let mut names = Vec::new();
+    for user_id in server.voice_members(channel_id).await {
+        if user_id != bot_id {
+            names.push(server.member_name(user_id).await.unwrap_or_else(|| user_id.to_string()));
+        }
+    }
+    names.sort();
+    let recorded = if names.is_empty() { "nobody yet".to_string() } else { names.join(", ") };
+    // voice channels have their own text chat
+    server.say(channel_id, &format!(
+        "I'm now recording this channel, and anyone in it can be dumped in chat. Being recorded: {}.\n\
+        If you don't want to be recorded, leave the channel or use /leave.",
+        recorded
+    )).await;
+
+    if let Some(chime) = announce_chime() {
+        server.play(&chime).await;
+    }
+}
+
+fn get_bytes(origin: &[i16]) -> Vec<u8> {
     let mut output = Vec::new();
     origin.iter().for_each(|&signal| signal.to_be_bytes().iter().for_each(|&byte| { output.push(byte) }));
     output
 }
 
-fn output_format() -> String {
-    match env::var("DISCORD_OUTPUT_FORMAT") {
-        Ok(custom_format) => custom_format,
-        Err(_) => "ogg".to_string()
+fn announce_enabled() -> bool {
+    match env::var("DISCORD_ANNOUNCE") {
+        Ok(value) => value != "false",
+        Err(_) => true
+    }
+}
+
+fn announce_chime() -> Option<String> {
+    env::var("DISCORD_ANNOUNCE_CHIME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{atomic::{AtomicBool, AtomicUsize}, Mutex as StdMutex},
+    };
+    use serenity::{
+        async_trait,
+        model::{permissions::Permissions, prelude::GuildId},
+        prelude::{RwLock, TypeMap},
+    };
+    use serde_json::Value;
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+    const BOT: UserId = UserId(100);
+    const MEMBER: UserId = UserId(200);
+    const OTHER: UserId = UserId(300);
+    const ADMIN: UserId = UserId(400); // the only one with Manage Server
+    const VOICE: ChannelId = ChannelId(10);
+    const OTHER_VOICE: ChannelId = ChannelId(11);
+    const TEXT: ChannelId = ChannelId(20);
+    const PROGRESS: MessageId = MessageId(30);
+
+    // a guild kept in memory: the bot's call, and whatever would have been sent, are only written down
+    struct FakeServer {
+        typemap: Arc<RwLock<TypeMap>>,
+        audit_log: String,
+        voice_states: StdMutex<HashMap<UserId, ChannelId>>,
+        names: HashMap<UserId, String>,
+        call: StdMutex<Option<ChannelId>>,
+        replies: StdMutex<Vec<String>>, // edits and follow ups, in order
+        said: StdMutex<Vec<(ChannelId, String)>>,
+        uploaded: StdMutex<Vec<String>>,
+    }
+
+    // a command used by someone in the fake guild
+    struct Fake {
+        server: Arc<FakeServer>,
+        data: CommandData,
+        member: UserId,
+    }
+
+    impl FakeServer {
+        fn new() -> Arc<Self> {
+            // the commands audit themselves, each guild in its own file so the tests don't read each other's
+            static GUILDS: AtomicUsize = AtomicUsize::new(0);
+            let audit_log = env::temp_dir()
+                .join(format!("smol_kevin-test-audit-{}-{}.log", std::process::id(), GUILDS.fetch_add(1, Ordering::SeqCst)))
+                .to_string_lossy()
+                .into_owned();
+            let mut typemap = TypeMap::new();
+            typemap.insert::<AuditLog>(audit_log.clone());
+            typemap.insert::<Lobbies>(Arc::new(RwLock::new(HashMap::default())));
+            typemap.insert::<FollowFlag>(Arc::new(Mutex::new(HashMap::default())));
+            typemap.insert::<JoinFlag>(Arc::new(Mutex::new(HashSet::default())));
+            typemap.insert::<ShutdownFlag>(Arc::new((AtomicBool::new(false), RwLock::new(()))));
+            typemap.insert::<EncodingQueue>(Arc::new(EncodingQueue::new(1)));
+            typemap.insert::<DumpCancels>(Arc::new(Mutex::new(HashMap::default())));
+            typemap.insert::<DumpDefaults>(DumpDefaults::default());
+            typemap.insert::<PermissionRules>(Arc::new(RwLock::new(HashMap::default())));
+            typemap.insert::<LiveMeters>(Arc::new(Mutex::new(HashMap::default())));
+            Arc::new(Self {
+                typemap: Arc::new(RwLock::new(typemap)),
+                audit_log,
+                voice_states: StdMutex::new(HashMap::new()),
+                names: [(BOT, "bot"), (MEMBER, "member"), (OTHER, "other")].iter()
+                    .map(|(user_id, name)| (*user_id, name.to_string()))
+                    .collect(),
+                call: StdMutex::new(None),
+                replies: StdMutex::new(Vec::new()),
+                said: StdMutex::new(Vec::new()),
+                uploaded: StdMutex::new(Vec::new()),
+            })
+        }
+
+        fn enter(&self, user_id: UserId, channel_id: ChannelId) {
+            self.voice_states.lock().unwrap().insert(user_id, channel_id);
+        }
+
+        fn command(self: &Arc<Self>, member: UserId, name: &str, options: &[(&str, Value)]) -> Fake {
+            let options = options.iter()
+                .map(|(name, value)| CommandOption { name: name.to_string(), value: Some(value.clone()), options: Vec::new() })
+                .collect();
+            Fake {
+                server: self.clone(),
+                data: CommandData { name: name.to_string(), options },
+                member,
+            }
+        }
+
+        fn replies(&self) -> Vec<String> {
+            self.replies.lock().unwrap().clone()
+        }
+
+        fn last_reply(&self) -> String {
+            self.replies.lock().unwrap().last().cloned().unwrap_or_default()
+        }
+
+        async fn lobby(&self) -> Option<Lobby> {
+            self.typemap.read().await.get::<Lobbies>().unwrap().read().await.get(&GUILD).cloned()
+        }
+
+        // what the receiver would have put in the lobby after the user spoke
+        async fn speak(&self, ssrc: u32, user_id: UserId, samples: usize) {
+            let lobby = self.lobby().await.expect("the bot is not in a call");
+            let mut buffer = Buffer::new(AudioFormat::DECODED, 10 * samples);
+            buffer.push_audio(&(0..samples).map(|sample| (sample % 100) as i16).collect());
+            lobby.0.lock().await.insert(ssrc, buffer);
+            lobby.1.lock().await.insert(ssrc, user_id);
+        }
+    }
+
+    impl Drop for FakeServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.audit_log);
+        }
+    }
+
+    #[async_trait]
+    impl Server for Fake {
+        fn guild_id(&self) -> GuildId {
+            GUILD
+        }
+
+        fn typemap(&self) -> &Arc<RwLock<TypeMap>> {
+            &self.server.typemap
+        }
+
+        async fn bot_id(&self) -> UserId {
+            BOT
+        }
+
+        async fn voice_channel(&self, user_id: UserId) -> Option<ChannelId> {
+            self.server.voice_states.lock().unwrap().get(&user_id).cloned()
+        }
+
+        async fn voice_members(&self, channel_id: ChannelId) -> Vec<UserId> {
+            self.server.voice_states.lock().unwrap().iter()
+                .filter(|(_, voice_channel_id)| **voice_channel_id == channel_id)
+                .map(|(user_id, _)| *user_id)
+                .collect()
+        }
+
+        async fn member_name(&self, user_id: UserId) -> Option<String> {
+            self.server.names.get(&user_id).cloned()
+        }
+
+        async fn join_voice(&self, channel_id: ChannelId) -> Result<(), ()> {
+            *self.server.call.lock().unwrap() = Some(channel_id);
+            self.server.enter(BOT, channel_id);
+            Ok(())
+        }
+
+        async fn listen(&self, _lobby: Lobby) {}
+
+        async fn leave_voice(&self) -> Option<Result<(), ()>> {
+            self.server.call.lock().unwrap().take()?;
+            self.server.voice_states.lock().unwrap().remove(&BOT);
+            Some(Ok(()))
+        }
+
+        async fn play(&self, _path: &str) {}
+
+        async fn say(&self, channel_id: ChannelId, message_content: &str) {
+            self.server.said.lock().unwrap().push((channel_id, message_content.to_string()));
+        }
+    }
+
+    #[async_trait]
+    impl Reply for Fake {
+        fn data(&self) -> &CommandData {
+            &self.data
+        }
+
+        fn channel_id(&self) -> ChannelId {
+            TEXT
+        }
+
+        fn member(&self) -> UserId {
+            self.member
+        }
+
+        fn member_roles(&self) -> &[RoleId] {
+            &[]
+        }
+
+        fn member_permissions(&self) -> Permissions {
+            if self.member == ADMIN {
+                Permissions::MANAGE_GUILD
+            } else {
+                Permissions::empty()
+            }
+        }
+
+        async fn edit(&self, message_content: &str) {
+            self.server.replies.lock().unwrap().push(message_content.to_string());
+        }
+
+        async fn follow_up(&self, message_content: &str) {
+            self.server.replies.lock().unwrap().push(message_content.to_string());
+        }
+
+        async fn follow_up_files(&self, files: &[(Vec<u8>, String)]) -> Result<(), ()> {
+            self.server.uploaded.lock().unwrap().extend(files.iter().map(|(_, name)| name.clone()));
+            Ok(())
+        }
+
+        async fn add_cancel_reaction(&self) -> Option<MessageId> {
+            Some(PROGRESS)
+        }
+    }
+
+    // the bot in the member's channel, with a fresh lobby
+    async fn joined() -> Arc<FakeServer> {
+        let server = FakeServer::new();
+        server.enter(MEMBER, VOICE);
+        join(server.command(MEMBER, "join", &[])).await;
+        server.replies.lock().unwrap().clear();
+        server
+    }
+
+    #[tokio::test]
+    async fn join_moves_to_the_member_and_announces_it() {
+        let server = FakeServer::new();
+        server.enter(MEMBER, VOICE);
+        server.enter(OTHER, VOICE);
+        join(server.command(MEMBER, "join", &[])).await;
+        assert_eq!(*server.call.lock().unwrap(), Some(VOICE));
+        assert_eq!(server.replies(), vec![format!("Joined {}", VOICE.mention())]);
+        assert!(server.lobby().await.is_some());
+        assert!(server.typemap.read().await.get::<JoinFlag>().unwrap().lock().await.contains(&GUILD));
+        let said = server.said.lock().unwrap().clone();
+        assert_eq!(said.len(), 1);
+        assert_eq!(said[0].0, VOICE);
+        assert!(said[0].1.contains("Being recorded: member, other."), "{}", said[0].1);
+    }
+
+    #[tokio::test]
+    async fn join_needs_a_voice_channel() {
+        let server = FakeServer::new();
+        join(server.command(MEMBER, "join", &[])).await;
+        assert_eq!(*server.call.lock().unwrap(), None);
+        assert_eq!(server.replies(), vec!["Error: you first need to be in a voice channel"]);
+    }
+
+    #[tokio::test]
+    async fn leave_needs_the_same_channel() {
+        let server = joined().await;
+        server.enter(OTHER, OTHER_VOICE);
+        leave(server.command(OTHER, "leave", &[])).await;
+        assert_eq!(*server.call.lock().unwrap(), Some(VOICE));
+        assert_eq!(server.last_reply(), "Error: You have to be in the same channel as the bot to remove it");
+
+        leave(server.command(MEMBER, "leave", &[])).await;
+        assert_eq!(*server.call.lock().unwrap(), None);
+        assert_eq!(server.last_reply(), format!("Left {}", VOICE.mention()));
+        assert!(server.lobby().await.is_none());
+
+        leave(server.command(MEMBER, "leave", &[])).await;
+        assert_eq!(server.last_reply(), "Error: The bot is not in a voice channel");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn dump_encodes_every_speaker() {
+        let server = joined().await;
+        server.speak(1, MEMBER, 9600).await;
+        server.speak(2, OTHER, 4800).await;
+        // someone the guild doesn't know is left out
+        server.speak(3, UserId(400), 4800).await;
+        dump(server.command(MEMBER, "dump", &[("format", Value::from("wav"))])).await;
+        let replies = server.replies();
+        assert_eq!(replies[0], "Extracting the audio...");
+        assert!(replies.contains(&"Encoding 0/2... (❌ or /cancel to stop)".to_string()), "{:?}", replies);
+        // ffmpeg may not be around to encode them, either way both are accounted for
+        let mut uploaded = server.uploaded.lock().unwrap().clone();
+        uploaded.sort();
+        if uploaded.is_empty() {
+            let last_reply = server.last_reply();
+            assert!(last_reply.starts_with("Error: could not encode the audio of"), "{}", last_reply);
+            assert!(last_reply.contains("member: ") && last_reply.contains("other: "), "{}", last_reply);
+        } else {
+            assert_eq!(uploaded, vec!["member.wav", "other.wav"]);
+        }
+        // dumping doesn't empty the buffers, and there is nothing left to cancel
+        assert_eq!(server.lobby().await.unwrap().0.lock().await.len(), 3);
+        assert!(server.typemap.read().await.get::<DumpCancels>().unwrap().lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_reaction_only_cancels_its_own_dump() {
+        let server = FakeServer::new();
+        let dump_cancels = server.typemap.read().await.get::<DumpCancels>().unwrap().clone();
+        let mut receivers = Vec::new();
+        for (number, message_id) in [(0, MessageId(30)), (1, MessageId(31))].iter() {
+            let (cancel, cancelled) = watch::channel(false);
+            dump_cancels.lock().await.insert(*number, RunningDump { guild_id: GUILD, user_id: MEMBER, message_id: Some(*message_id), cancel });
+            receivers.push(cancelled);
+        }
+        // someone else's reaction does nothing
+        assert!(!cancel_by_reaction(&dump_cancels, MessageId(30), OTHER).await);
+        assert!(!*receivers[0].borrow());
+        assert!(cancel_by_reaction(&dump_cancels, MessageId(30), MEMBER).await);
+        assert!(*receivers[0].borrow());
+        assert!(!*receivers[1].borrow());
+
+        // while /cancel stops all of them
+        cancel(server.command(MEMBER, "cancel", &[])).await;
+        assert!(*receivers[1].borrow());
+        assert_eq!(server.last_reply(), "Cancelling 2 dumps...");
+        dump_cancels.lock().await.clear();
+        cancel(server.command(MEMBER, "cancel", &[])).await;
+        assert_eq!(server.last_reply(), "Error: there is no dump to cancel");
+    }
+
+    #[tokio::test]
+    async fn dump_refuses_a_mix_of_cut_pauses() {
+        let server = joined().await;
+        server.speak(1, MEMBER, 9600).await;
+        dump(server.command(MEMBER, "dump", &[("mix", Value::from(true)), ("max_pause", Value::from(500))])).await;
+        assert_eq!(server.replies().len(), 1);
+        assert!(server.last_reply().starts_with("Error: mix needs every pause kept whole"));
+    }
+
+    #[tokio::test]
+    async fn dump_refuses_a_timeline_of_cut_pauses() {
+        let server = joined().await;
+        server.speak(1, MEMBER, 9600).await;
+        dump(server.command(MEMBER, "dump", &[("timeline", Value::from("vtt")), ("pauses", Value::from(false))])).await;
+        assert_eq!(server.replies().len(), 1);
+        assert!(server.last_reply().starts_with("Error: timeline needs every pause kept whole"));
+    }
+
+    #[tokio::test]
+    async fn dump_without_a_call_or_anyone_talking() {
+        let server = FakeServer::new();
+        dump(server.command(MEMBER, "dump", &[])).await;
+        assert_eq!(server.replies(), vec!["Error: The bot is not in a voice channel"]);
+
+        let server = joined().await;
+        dump(server.command(MEMBER, "dump", &[])).await;
+        assert_eq!(server.last_reply(), "Done!");
+        assert!(server.uploaded.lock().unwrap().is_empty());
+        // still logged, without anyone's audio
+        let entries = audit::query(&server.audit_log, GUILD, Some(MEMBER), Some("dump"), 10);
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].exported.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clear_empties_the_buffers() {
+        let server = FakeServer::new();
+        clear(server.command(MEMBER, "clear", &[])).await;
+        assert_eq!(server.replies(), vec!["Error: The bot is not in a voice channel"]);
+
+        let server = joined().await;
+        server.speak(1, MEMBER, 9600).await;
+        clear(server.command(MEMBER, "clear", &[])).await;
+        assert!(server.lobby().await.unwrap().0.lock().await.is_empty());
+        assert_eq!(server.last_reply(), "The buffer has been cleared. No need to thank me");
+    }
+
+    #[tokio::test]
+    async fn follow_and_unfollow() {
+        let server = FakeServer::new();
+        server.enter(MEMBER, VOICE);
+        follow(server.command(MEMBER, "follow", &[])).await;
+        assert_eq!(server.replies(), vec![format!("The bot will now follow {}", MEMBER.mention())]);
+        assert_eq!(*server.call.lock().unwrap(), Some(VOICE));
+        let follow_flag = server.typemap.read().await.get::<FollowFlag>().unwrap().clone();
+        assert_eq!(follow_flag.lock().await.get(&GUILD), Some(&MEMBER));
+
+        // only the one being followed can stop it
+        unfollow(server.command(OTHER, "unfollow", &[])).await;
+        assert_eq!(server.last_reply(), format!("I don't even know who {} is.", OTHER.mention()));
+        assert_eq!(follow_flag.lock().await.get(&GUILD), Some(&MEMBER));
+        unfollow(server.command(MEMBER, "unfollow", &[])).await;
+        assert_eq!(server.last_reply(), format!("The bot has stopped following {}.", MEMBER.mention()));
+        assert_eq!(follow_flag.lock().await.get(&GUILD), None);
+    }
+
+    #[tokio::test]
+    async fn audit_shows_denied_attempts_to_admins() {
+        let server = FakeServer::new();
+        audit::record(&server.command(MEMBER, "stats", &[]), Vec::new()).await;
+        audit::record_denied(&server.command(OTHER, "clear", &[])).await;
+
+        audit(server.command(MEMBER, "audit", &[])).await;
+        assert_eq!(server.last_reply(), "Error: you need the Manage Server permission to read the audit log");
+        audit(server.command(ADMIN, "audit", &[])).await;
+        let lines: Vec<String> = server.last_reply().lines().map(String::from).collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!("{} used /stats in {}", MEMBER.mention(), TEXT.mention())));
+        assert!(lines[1].ends_with(&format!("{} was denied /clear in {}", OTHER.mention(), TEXT.mention())));
+    }
+
+    #[test]
+    fn audit_keeps_the_newest_lines_that_fit_an_embed() {
+        let lines: Vec<String> = (0..200).map(|i| format!("{:03}{}", i, "x".repeat(97))).collect();
+        let description = fit_description(&lines);
+        assert!(description.chars().count() <= EMBED_DESCRIPTION_LIMIT);
+        let kept: Vec<&str> = description.lines().collect();
+        assert_eq!(kept[0], format!("{} older entries left out", 200 - (kept.len() - 1)));
+        assert_eq!(kept[kept.len() - 1], lines[199]);
+        assert_eq!(kept[1], lines[200 - (kept.len() - 1)]);
+
+        let few = &lines[..3];
+        assert_eq!(fit_description(few), few.join("\n"));
+    }
+
+    #[tokio::test]
+    async fn listing_permissions_changes_nothing() {
+        let server = FakeServer::new();
+        let rules = server.typemap.read().await.get::<PermissionRules>().unwrap().clone();
+        let command = || Fake {
+            server: server.clone(),
+            data: CommandData {
+                name: "permissions".to_string(),
+                options: vec![CommandOption { name: "list".to_string(), value: None, options: Vec::new() }],
+            },
+            member: ADMIN,
+        };
+        permissions(command()).await;
+        assert_eq!(server.last_reply(), "Everyone can use every command");
+        assert!(rules.read().await.is_empty());
+
+        let rule = PermissionRule { roles: [RoleId(5)].iter().cloned().collect(), permissions: 0 };
+        rules.write().await.entry(GUILD).or_default().insert("dump".to_string(), rule);
+        permissions(command()).await;
+        assert_eq!(server.last_reply(), format!("/dump: {}", RoleId(5).mention()));
+    }
+}
@@ -1,10 +1,20 @@
+mod archive;
+mod audit;
 mod commands;
+mod processing;
+mod registration;
+mod registry;
+mod shutdown;
+mod snapshot;
+mod stats;
 mod structs;
+mod timeline;
 
 use std::{
     collections::HashMap,
     env,
-    sync::Arc};
+    sync::Arc,
+    time::{Duration, Instant}};
 use serenity::{
     async_trait,
     client::{
@@ -18,11 +28,8 @@ use serenity::{
             Ready
         },
         prelude::UserId,
-        interactions::{
-            ApplicationCommand,
-            Interaction,
-        },
-        id::ApplicationId
+        channel::{Message, Reaction, ReactionType},
+        interactions::Interaction,
     },
 };
 use songbird::{
@@ -44,13 +51,12 @@ use tokio::{
     sync::RwLock,
     sync::Mutex,
 };
-use dotenv;
 use crate::structs::*;
 use serenity::model::id::GuildId;
 use serenity::model::prelude::VoiceState;
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use crate::commands::move_to;
-use serenity::model::interactions::ApplicationCommandOptionType;
 
 struct Handler;
 
@@ -58,46 +64,8 @@ struct Handler;
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         ctx.shard.set_activity(Some(Activity::listening("...YOU...")));
-        let update = discord_update();
-
-        if update {
-            let _ = ApplicationCommand::create_global_application_command(&ctx, |a| {
-                a.name("dump")
-                    .description("Dumps the contents of the audio buffer in chat.")
-                    .create_option(|opt| {
-                        opt.name("pauses")
-                            .description("[defaults to true] will include pauses between instanses of speech from the user.")
-                            .kind(ApplicationCommandOptionType::Boolean)
-                    })
-                    /*
-                    .create_interaction_option(|opt| {
-                    opt.name("merge")
-                        .description("[defaults to false] will merge all the users' audio into one single track.")
-                        .kind(ApplicationCommandOptionType::Boolean)
-
-                    })*/
-
-            }).await;
-            let _ = ApplicationCommand::create_global_application_command(&ctx, |a| {
-                a.name("clear")
-                    .description("Clears the audio buffer.")
-            }).await;
-            let _ = ApplicationCommand::create_global_application_command(&ctx, |a| {
-                a.name("join")
-                    .description("Makes the bot join your voice channel.")
-            }).await;
-            let _ = ApplicationCommand::create_global_application_command(&ctx, |a| {
-                a.name("leave")
-                    .description("Makes the bot leave your voice channel.")
-            }).await;
-            let _ = ApplicationCommand::create_global_application_command(&ctx, |a| {
-                a.name("follow")
-                    .description("Makes the bot follow you around.")
-            }).await;
-            let _ = ApplicationCommand::create_global_application_command(&ctx, |a| {
-                a.name("unfollow")
-                    .description("Makes the bot stop following you.")
-            }).await;
+        if discord_update() {
+            registration::register(&ctx).await;
         }
         println!("{} is online!", ready.user.name);
     }
@@ -107,19 +75,41 @@ impl EventHandler for Handler {
         let user_id = new.user_id;
         let guild_id = guild_id.unwrap();
         let guild = ctx.cache.guild(guild_id).await.unwrap();
+        let server = CachedServer::new(ctx.clone(), guild_id);
         if user_id == ctx.cache.current_user_id().await {
             let flags = data_read.get::<JoinFlag>().expect("Typemap incomplete").clone();
             let mut flags = flags.lock().await;
-            if flags.remove(&guild_id) == false {
-                if let Some(old_vs) = old {
-                    drop(flags);
-                    let _ = move_to(&ctx, guild, old_vs.channel_id.unwrap()).await;
+            let old_channel_id = old.and_then(|vs| vs.channel_id);
+            // without a join flag the bot did not move itself, someone else did
+            if !flags.remove(&guild_id) && old_channel_id != new.channel_id {
+                drop(flags);
+                match new.channel_id {
+                    Some(channel_id) => println!("{}: the bot was moved from {:?} to {} by someone else", guild.name, old_channel_id, channel_id),
+                    None => println!("{}: the bot was disconnected from {:?} by someone else", guild.name, old_channel_id),
+                }
+                let rejoin_policy = *data_read.get::<RejoinPolicy>().expect("Typemap incomplete");
+                let target_channel_id = match rejoin_policy {
+                    RejoinPolicy::Always => old_channel_id,
+                    RejoinPolicy::Never => None,
+                    RejoinPolicy::Following => follow_flag.lock().await.get(&guild_id)
+                        .and_then(|followed| guild.voice_states.get(followed))
+                        .and_then(|vs| vs.channel_id),
+                };
+                if let Some(channel_id) = target_channel_id {
+                    let _ = move_to(&server, channel_id).await;
+                } else if new.channel_id.is_none() {
+                    // the call is over, drop it along with its buffer as leave would
+                    let manager = songbird::get(&ctx).await
+                        .expect("Songbird Voice client placed in at initialisation.").clone();
+                    let _ = manager.remove(guild_id).await;
+                    let buffers_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
+                    buffers_lock.write().await.remove(&guild_id);
                 }
             }
         } else if let Some(followed) = follow_flag.lock().await.get(&guild_id) {
             if followed == &user_id {
                 if let Some(channel_id) = new.channel_id {
-                    let _ = move_to(&ctx, guild, channel_id).await;
+                    let _ = move_to(&server, channel_id).await;
                 }
             }
         };
@@ -127,21 +117,141 @@ impl EventHandler for Handler {
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Ok(response) = Response::new(&ctx, interaction).await {
-            match response.data() {
-                None => {}
-                Some(command) => match command.name.as_str() {
-                    "dump"  => commands::dump(&ctx, response).await,
-                    "clear" => commands::clear(&ctx, response).await,
-                    "join"  => commands::join(&ctx, response).await,
-                    "leave" => commands::leave(&ctx, response).await,
-                    "follow" => commands::follow(&ctx, response).await,
-                    "unfollow" => commands::unfollow(&ctx, response).await,
-                    _ => {}
-                }
+            dispatch(response).await;
+        }
+    }
+
+    async fn message(&self, ctx: Context, message: Message) {
+        if let Ok(response) = Response::from_message(&ctx, message).await {
+            dispatch(response).await;
+        }
+    }
+
+    // the cancel reaction on the progress reply of a dump
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        if reaction.emoji != ReactionType::from(CANCEL_REACTION) {
+            return;
+        }
+        if let Some(user_id) = reaction.user_id {
+            let dump_cancels = ctx.data.read().await.get::<DumpCancels>().expect("Typemap incomplete").clone();
+            commands::cancel_by_reaction(&dump_cancels, reaction.message_id, user_id).await;
+        }
+    }
+
+}
+
+// slash and prefix commands both end up here
+async fn dispatch(response: Response) {
+    let command = match registry::find(&response.data().name) {
+        Some(command) => command,
+        None => return,
+    };
+    let shutdown_flag = response.typemap().read().await.get::<ShutdownFlag>().expect("Typemap incomplete").clone();
+    if shutdown_flag.0.load(Ordering::SeqCst) {
+        response.edit("Error: the bot is shutting down").await;
+        return;
+    }
+    // shutting down waits for this to be dropped
+    let _running = shutdown_flag.1.read().await;
+    if !is_allowed(&response, command.name).await {
+        if command.audited {
+            audit::record_denied(&response).await;
+        }
+        response.edit(&format!("Error: you are not allowed to use /{}", command.name)[..]).await;
+        return;
+    }
+    if command.audited && !command.audits_itself {
+        audit::record(&response, Vec::new()).await;
+    }
+    (command.handler)(response).await;
+}
+
+// checks the guild's permission rules, admins can always use every command
+async fn is_allowed(response: &impl Reply, command_name: &str) -> bool {
+    let member_permissions = response.member_permissions();
+    if member_permissions.administrator() {
+        return true;
+    }
+    let data_read = response.typemap().read().await;
+    let rules_lock = data_read.get::<PermissionRules>().expect("Typemap incomplete").clone();
+    let rules = rules_lock.read().await;
+    match rules.get(&response.guild_id()).and_then(|guild_rules| guild_rules.get(command_name)) {
+        Some(rule) => rule.allows(response.member_roles(), member_permissions),
+        None => true,
+    }
+}
+
+// the receiver's reactions to voice events, kept apart from songbird's event types so they can be driven directly
+impl Receiver {
+    async fn voice_packet(&self, ssrc: u32, audio: &Vec<i16>) {
+        let buffer = &mut self.lobby.0.lock().await;
+        if let Some(buffer) = buffer.get_mut(&ssrc) {
+            buffer.push_audio(audio);
+        } else {
+            let mut new_buffer = Buffer::new(AudioFormat::DECODED, self.buffer_size);
+            new_buffer.push_audio(audio);
+            buffer.insert(ssrc, new_buffer);
+        }
+        if let Some(user_id) = self.lobby.1.lock().await.get(&ssrc) {
+            self.lobby.3.lock().await.heard(*user_id, Instant::now());
+        }
+    }
+
+    async fn speaking_state_update(&self, ssrc: u32, user_id: UserId) {
+        let audio_buffer = &mut self.lobby.0.lock().await;
+        let ssrc_to_user_map = &mut self.lobby.1.lock().await;
+        let restored = &mut self.lobby.2.lock().await;
+        // a user whose ssrc changed keeps their old buffer, and gets back the one from before a restart
+        let old_ssrcs: Vec<u32> = ssrc_to_user_map.iter()
+            .filter(|(mapped_ssrc, mapped_user_id)| **mapped_user_id == user_id && **mapped_ssrc != ssrc)
+            .map(|(mapped_ssrc, _)| *mapped_ssrc)
+            .collect();
+        let mut buffers: Vec<Buffer> = restored.remove(&user_id).into_iter().collect();
+        for old_ssrc in old_ssrcs {
+            ssrc_to_user_map.remove(&old_ssrc);
+            buffers.extend(audio_buffer.remove(&old_ssrc));
+        }
+        // packets can come in under the new ssrc before this, they go after the older audio
+        buffers.extend(audio_buffer.remove(&ssrc));
+        let buffer = buffers.into_iter().reduce(|mut older, newer| {
+            older.append(&newer);
+            older
+        });
+        if let Some(buffer) = buffer {
+            audio_buffer.insert(ssrc, buffer);
+        }
+        ssrc_to_user_map.insert(ssrc, user_id);
+    }
+
+    async fn speaking_update(&self, ssrc: u32, speaking: bool) {
+        let audio_buffer = &mut self.lobby.0.lock().await;
+        if let Some(buffer) = audio_buffer.get_mut(&ssrc) {
+            if speaking {
+                buffer.push_silence_end();
+            } else {
+                buffer.push_silence();
+            }
+        }
+        // talking is only counted once their audio comes in
+        if !speaking {
+            if let Some(user_id) = self.lobby.1.lock().await.get(&ssrc) {
+                self.lobby.3.lock().await.stopped(*user_id, Instant::now());
             }
         }
     }
 
+    async fn client_disconnect(&self, user_id: UserId) {
+        let audio_buffer = &mut self.lobby.0.lock().await;
+        let ssrc_to_user_map = &mut self.lobby.1.lock().await;
+        self.lobby.2.lock().await.remove(&user_id);
+        self.lobby.3.lock().await.stopped(user_id, Instant::now());
+        // loops the entire buffer in case the ssrc changed midway through
+        for (mapped_ssrc, mapped_user_id) in ssrc_to_user_map.iter() {
+            if *mapped_user_id == user_id {
+                audio_buffer.remove(mapped_ssrc);
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -153,14 +263,7 @@ impl VoiceEventHandler for Receiver {
                 // An event which fires for every received audio packet,
                 // containing the decoded data.
                 if let Some(audio) = audio {
-                    let buffer = &mut self.lobby.0.lock().await;
-                    if let Some(buffer) = buffer.get_mut(&packet.ssrc) {
-                        buffer.push_audio(audio);
-                    } else {
-                        let mut new_buffer = Buffer::new();
-                        new_buffer.push_audio(audio);
-                        buffer.insert(packet.ssrc, new_buffer);
-                    }
+                    self.voice_packet(packet.ssrc, audio).await;
                 }
             }
 
@@ -169,10 +272,8 @@ impl VoiceEventHandler for Receiver {
             ) => {
                 // You can implement your own logic here to handle a user who has joined the
                 // voice channel e.g., allocate structures, map their SSRC to User ID.
-                let ssrc_to_user_map = &mut self.lobby.1.lock().await;
                 if let Some(user_id) = user_id {
-                    let id = user_id.0;
-                    ssrc_to_user_map.insert(*ssrc, UserId(id));
+                    self.speaking_state_update(*ssrc, UserId(user_id.0)).await;
                 }
             }
 
@@ -180,25 +281,11 @@ impl VoiceEventHandler for Receiver {
                 ssrc,
                 speaking
             } => {
-                let audio_buffer = &mut self.lobby.0.lock().await;
-                if let Some(buffer) = audio_buffer.get_mut(ssrc) {
-                    if *speaking {
-                        buffer.push_silence_end();
-                    } else {
-                        buffer.push_silence();
-                    }
-                }
+                self.speaking_update(*ssrc, *speaking).await;
             }
 
             Ctx::ClientDisconnect(ClientDisconnect { user_id, .. }) => {
-                let ssrc_to_user_map = &mut self.lobby.1.lock().await;
-                // loops the entire buffer in case the ssrc changed midway through
-                for (mapped_ssrc, mapped_user_id) in ssrc_to_user_map.iter() {
-                    if mapped_user_id.0 == user_id.0 {
-                        let audio_buffer = &mut self.lobby.0.lock().await;
-                        audio_buffer.remove(mapped_ssrc);
-                    }
-                }
+                self.client_disconnect(UserId(user_id.0)).await;
             }
 
             _ => {
@@ -215,6 +302,8 @@ impl VoiceEventHandler for Receiver {
 async fn main() {
     dotenv::dotenv().ok();
     let token = discord_token();
+    let dump_defaults = dump_defaults();
+    let rejoin_policy = rejoin_policy();
     // Here, we need to configure Songbird to decode all incoming voice packets.
     // If you want, you can do this on a per-call basis---here, we need it to
     // read the audio data that other people are sending us!
@@ -227,7 +316,7 @@ async fn main() {
     let mut client = Client::builder(&token)
         .event_handler(Handler)
         .application_id(discord_app_id())
-        .register_songbird_with(songbird.into())
+        .register_songbird_with(songbird.clone())
         .await
         .expect("Err creating client");
 
@@ -236,8 +325,23 @@ async fn main() {
         data.insert::<Lobbies>(Arc::new(RwLock::new(HashMap::default())));
         data.insert::<FollowFlag>(Arc::new(Mutex::new(HashMap::default())));
         data.insert::<JoinFlag>(Arc::new(Mutex::new(HashSet::default())));
+        data.insert::<ShutdownFlag>(Arc::new((AtomicBool::new(false), RwLock::new(()))));
+        data.insert::<RejoinPolicy>(rejoin_policy);
+        data.insert::<AuditLog>(audit_log());
+        data.insert::<PermissionRules>(Arc::new(RwLock::new(load_permission_rules())));
+        data.insert::<EncodingQueue>(Arc::new(EncodingQueue::new(encoders())));
+        data.insert::<DumpCancels>(Arc::new(Mutex::new(HashMap::default())));
+        data.insert::<DumpDefaults>(dump_defaults);
+        data.insert::<LiveMeters>(Arc::new(Mutex::new(HashMap::default())));
     }
 
+    let data = client.data.clone();
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        shutdown::wait_for_signal().await;
+        shutdown::shutdown(data, shard_manager, songbird).await;
+    });
+
     let _ = client.start().await.map_err(|why| println!("Client ended: {:?}", why));
 }
 
@@ -247,9 +351,59 @@ fn discord_token() -> String {
 }
 
 fn discord_update() -> bool {
-    match env::var("DISCORD_UPDATE") {
-        Ok(_) => true,
-        Err(_) => false
+    env::var("DISCORD_UPDATE").is_ok()
+}
+
+fn rejoin_policy() -> RejoinPolicy {
+    match env::var("DISCORD_REJOIN_POLICY") {
+        Ok(policy) => match &policy.to_lowercase()[..] {
+            "always" => RejoinPolicy::Always,
+            "never" => RejoinPolicy::Never,
+            "following" => RejoinPolicy::Following,
+            _ => panic!("DISCORD_REJOIN_POLICY must be one of always, never or following")
+        },
+        Err(_) => RejoinPolicy::Following
+    }
+}
+
+// every setting is checked here, so that a bad one stops the bot instead of every dump
+fn dump_defaults() -> DumpDefaults {
+    let mut defaults = DumpDefaults {
+        format: output_format(),
+        ..DumpDefaults::default()
+    };
+    if let Ok(secs) = env::var("DISCORD_MAX_SILENCE_GAP") {
+        defaults.max_pause = Some(seconds(&secs)
+            .expect("make sure the max silence gap is a valid number of seconds!"));
+    }
+    if let Ok(secs) = env::var("DISCORD_MAX_SILENCE") {
+        defaults.max_silence = seconds(&secs)
+            .expect("make sure the max silence is a valid number of seconds!");
+    }
+    if let Ok(bitrate) = env::var("DISCORD_OUTPUT_BITRATE") {
+        defaults.bitrate = bitrate.parse().ok().filter(|bitrate| *bitrate > 0)
+            .expect("make sure the output bitrate is a valid number of kbps!");
+    }
+    if let Ok(rate) = env::var("DISCORD_OUTPUT_SAMPLE_RATE") {
+        defaults.sample_rate = Some(rate.parse().ok().filter(|rate| *rate > 0)
+            .expect("make sure the output sample rate is valid!"));
+    }
+    if let Ok(channels) = env::var("DISCORD_OUTPUT_CHANNELS") {
+        defaults.channels = Some(channels.parse().ok().filter(|channels| *channels > 0)
+            .expect("make sure the output channels are valid!"));
+    }
+    defaults
+}
+
+// a duration in seconds, which can't be negative
+fn seconds(secs: &str) -> Option<Duration> {
+    secs.parse().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+fn audit_log() -> String {
+    match env::var("DISCORD_AUDIT_LOG") {
+        Ok(path) => path,
+        Err(_) => "audit.log".to_string()
     }
 }
 
@@ -258,4 +412,152 @@ fn discord_app_id() -> u64 {
         Ok(id) => id.parse().expect("application id is not a valid id"),
         Err(_) => panic!("Expected an application id (DISCORD_APP_ID) in the environment")
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::stats::Session;
+    use super::*;
+
+    const PACKET: usize = 40; // samples in each generated packet, 20 stereo frames
+
+    fn receiver(buffer_size: usize) -> Receiver {
+        Receiver {
+            lobby: Arc::new((Mutex::new(HashMap::new()), Mutex::new(HashMap::new()), Mutex::new(HashMap::new()), Mutex::new(Session::new()))),
+            buffer_size,
+        }
+    }
+
+    // a quiet sine, different for every speaker and packet so the order can be checked
+    fn packet(speaker: u32, index: usize) -> Vec<i16> {
+        (0..PACKET).map(|sample| {
+            let phase = (index * PACKET + sample) as f64 * (speaker as f64 + 1.0) * 0.01;
+            (phase.sin() * 1000.0) as i16 + speaker as i16 * 2000
+        }).collect()
+    }
+
+    async fn pop(receiver: &Receiver, ssrc: u32) -> (Vec<i16>, Vec<i16>, usize) {
+        let lobby = receiver.lobby.0.lock().await;
+        let buffer = lobby.get(&ssrc).expect("no buffer for the ssrc");
+        let (uncompressed, segments) = buffer.pop_uncompressed(None, Duration::MAX);
+        (buffer.pop_compressed().0, uncompressed, segments.len())
+    }
+
+    #[tokio::test]
+    async fn speakers_get_their_own_audio() {
+        let receiver = receiver(100 * PACKET);
+        receiver.speaking_state_update(1, UserId(10)).await;
+        receiver.speaking_state_update(2, UserId(20)).await;
+        let mut sent: HashMap<u32, Vec<i16>> = HashMap::new();
+        for index in 0..6 {
+            for ssrc in [1, 2] {
+                let audio = packet(ssrc, index);
+                sent.entry(ssrc).or_default().extend(&audio);
+                receiver.voice_packet(ssrc, &audio).await;
+            }
+            // the first one pauses halfway through
+            if index == 2 {
+                receiver.speaking_update(1, false).await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                receiver.speaking_update(1, true).await;
+            }
+        }
+        let (compressed, uncompressed, segments) = pop(&receiver, 1).await;
+        assert_eq!(compressed, sent[&1]);
+        assert!(uncompressed.len() >= compressed.len() + AudioFormat::DECODED.samples(Duration::from_millis(20)));
+        assert_eq!(segments, 2);
+        let (compressed, uncompressed, segments) = pop(&receiver, 2).await;
+        assert_eq!(compressed, sent[&2]);
+        assert_eq!(uncompressed, compressed);
+        assert_eq!(segments, 1);
+        // the pause was too short to end the turn, and the second one started while the first was talking
+        let session = receiver.lobby.3.lock().await.stats(Instant::now());
+        assert_eq!(session.len(), 2);
+        assert!(session.iter().all(|speaker| speaker.turns == 1));
+        assert_eq!(session.iter().map(|speaker| speaker.interruptions).sum::<usize>(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_reconnect_keeps_the_buffer() {
+        let receiver = receiver(100 * PACKET);
+        receiver.speaking_state_update(1, UserId(10)).await;
+        receiver.voice_packet(1, &packet(1, 0)).await;
+        receiver.speaking_update(1, false).await;
+        // the same user comes back with a new ssrc
+        receiver.speaking_state_update(3, UserId(10)).await;
+        receiver.speaking_update(3, true).await;
+        receiver.voice_packet(3, &packet(1, 1)).await;
+        assert!(!receiver.lobby.0.lock().await.contains_key(&1));
+        assert_eq!(receiver.lobby.1.lock().await.get(&3), Some(&UserId(10)));
+        assert_eq!(receiver.lobby.1.lock().await.get(&1), None);
+        let (compressed, _, segments) = pop(&receiver, 3).await;
+        assert_eq!(compressed, [packet(1, 0), packet(1, 1)].concat());
+        assert_eq!(segments, 2);
+    }
+
+    #[tokio::test]
+    async fn a_restored_buffer_goes_back_to_its_user() {
+        let receiver = receiver(100 * PACKET);
+        let mut restored = Buffer::new(AudioFormat::DECODED, 100 * PACKET);
+        restored.push_audio(&packet(1, 0));
+        restored.push_padding(Duration::from_secs(1));
+        restored.push_silence();
+        receiver.lobby.2.lock().await.insert(UserId(10), restored);
+        // the first packets under the new ssrc can come in before it is known whose they are
+        receiver.voice_packet(7, &packet(1, 1)).await;
+        receiver.speaking_state_update(7, UserId(10)).await;
+        receiver.voice_packet(7, &packet(1, 2)).await;
+        assert!(receiver.lobby.2.lock().await.is_empty());
+        let (compressed, uncompressed, segments) = pop(&receiver, 7).await;
+        assert_eq!(compressed, [packet(1, 0), packet(1, 1), packet(1, 2)].concat());
+        assert!(uncompressed.len() >= compressed.len() + AudioFormat::DECODED.samples(Duration::from_secs(1)));
+        assert_eq!(segments, 2);
+    }
+
+    #[tokio::test]
+    async fn a_disconnect_only_drops_that_user() {
+        let receiver = receiver(100 * PACKET);
+        receiver.speaking_state_update(1, UserId(10)).await;
+        receiver.speaking_state_update(2, UserId(20)).await;
+        receiver.voice_packet(1, &packet(1, 0)).await;
+        receiver.voice_packet(2, &packet(2, 0)).await;
+        receiver.client_disconnect(UserId(10)).await;
+        assert!(!receiver.lobby.0.lock().await.contains_key(&1));
+        assert_eq!(pop(&receiver, 2).await.0, packet(2, 0));
+        // talking again starts a fresh buffer
+        receiver.voice_packet(1, &packet(1, 1)).await;
+        assert_eq!(pop(&receiver, 1).await.0, packet(1, 1));
+    }
+
+    #[tokio::test]
+    async fn the_buffer_keeps_the_newest_audio_when_it_wraps() {
+        // sizes that do and don't line up with the packets
+        for buffer_size in [4 * PACKET, 4 * PACKET + 7, PACKET / 3] {
+            let receiver = receiver(buffer_size);
+            receiver.speaking_state_update(1, UserId(10)).await;
+            let mut sent = Vec::new();
+            for index in 0..11 {
+                let audio = packet(1, index);
+                sent.extend(&audio);
+                receiver.voice_packet(1, &audio).await;
+                if index % 3 == 0 {
+                    receiver.speaking_update(1, false).await;
+                    receiver.speaking_update(1, true).await;
+                }
+            }
+            let (compressed, _, _) = pop(&receiver, 1).await;
+            assert!(compressed.len() <= buffer_size);
+            assert!(compressed.len() >= buffer_size.saturating_sub(4));
+            assert!(sent.ends_with(&compressed), "size {}", buffer_size);
+        }
+    }
+
+    #[test]
+    fn seconds_must_be_a_real_duration() {
+        assert_eq!(seconds("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(seconds("0"), Some(Duration::ZERO));
+        assert_eq!(seconds("-1"), None);
+        assert_eq!(seconds("NaN"), None);
+        assert_eq!(seconds("inf"), None);
+        assert_eq!(seconds("soon"), None);
+    }
+}
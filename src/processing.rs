@@ -0,0 +1,294 @@
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+use crate::structs::Track;
+
+// what dumps are normalised to, the ebu r128 target
+const TARGET_LOUDNESS: f64 = -23.0; // LUFS
+const MAX_PEAK: f64 = 0.89; // about -1 dBFS, normalising never pushes a peak past this
+const HIGH_PASS_FREQUENCY: f64 = 80.0; // Hz, below the lowest voices
+const GATE_THRESHOLD: f64 = -45.0; // dBFS
+const GATE_RELEASE: f64 = 0.1; // seconds for the gate to close
+const FRAME: f64 = 0.02; // seconds of audio the gate and denoise look at at once
+const NOISE_GAP: f64 = -20.0; // dB, frames this far under the speech level are taken as noise
+const MAX_ATTENUATION: f64 = -20.0; // dB, denoise never turns a frame down more than this
+
+// the steps that can run on a track before it's encoded, in the order they run
+#[derive(Clone, Copy, Default)]
+pub struct Processing {
+    pub high_pass: bool,
+    pub denoise: bool,
+    pub gate: bool,
+    pub normalize: bool,
+}
+
+impl Processing {
+    pub fn is_empty(&self) -> bool {
+        !(self.high_pass || self.denoise || self.gate || self.normalize)
+    }
+
+    pub fn apply(&self, track: &mut Track) {
+        if self.is_empty() || track.samples.is_empty() {
+            return;
+        }
+        let rate = track.format.rate as f64;
+        let channels = track.format.channels as usize;
+        let mut samples: Vec<f64> = track.samples.iter().map(|sample| *sample as f64 / 32768.0).collect();
+        if self.high_pass {
+            Biquad::high_pass(HIGH_PASS_FREQUENCY, FRAC_1_SQRT_2, rate).run(&mut samples, channels);
+        }
+        if self.denoise {
+            denoise(&mut samples, channels, rate);
+        }
+        if self.gate {
+            gate(&mut samples, channels, rate);
+        }
+        if self.normalize {
+            normalize(&mut samples, channels, rate);
+        }
+        track.samples = samples.iter()
+            .map(|sample| (sample * 32768.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16)
+            .collect();
+    }
+}
+
+// a second order filter, with the coefficients from the audio eq cookbook
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn high_pass(frequency: f64, q: f64, rate: f64) -> Self {
+        let w0 = 2.0 * PI * frequency / rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b: [(1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+        }
+    }
+
+    // the two stages of the bs.1770 k-weighting, worked out for any rate the way libebur128 does.
+    // they aren't quite the cookbook shelf and high pass, which would be a quarter of a dB off
+    fn k_weighting(rate: f64) -> [Self; 2] {
+        let k = (PI * 1681.974450955533 / rate).tan();
+        let q = 0.7071752369554196;
+        let vh = 10f64.powf(3.999843853973347 / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Self {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+        let k = (PI * 38.13547087602444 / rate).tan();
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Self {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        };
+        [shelf, high_pass]
+    }
+
+    // every channel of the interleaved samples gets its own state
+    fn run(&self, samples: &mut [f64], channels: usize) {
+        let mut state = vec![[0.0; 2]; channels];
+        for frame in samples.chunks_mut(channels) {
+            for (sample, state) in frame.iter_mut().zip(state.iter_mut()) {
+                let input = *sample;
+                let output = self.b[0] * input + state[0];
+                state[0] = self.b[1] * input - self.a[0] * output + state[1];
+                state[1] = self.b[2] * input - self.a[1] * output;
+                *sample = output;
+            }
+        }
+    }
+}
+
+// integrated loudness as in itu-r bs.1770, which r128 is built on: k-weighting, 400ms blocks and two gates
+fn loudness(samples: &[f64], channels: usize, rate: f64) -> Option<f64> {
+    let mut weighted = samples.to_vec();
+    for stage in Biquad::k_weighting(rate).iter() {
+        stage.run(&mut weighted, channels);
+    }
+    let block = (0.4 * rate) as usize * channels;
+    let step = (0.1 * rate) as usize * channels;
+    if block == 0 || step == 0 || weighted.len() < block {
+        return None;
+    }
+    // the mean squares of every channel are added together, every channel has the same weight here
+    let powers: Vec<f64> = (0..=(weighted.len() - block) / step)
+        .map(|index| {
+            let block = &weighted[index * step..index * step + block];
+            block.iter().map(|sample| sample * sample).sum::<f64>() / (block.len() / channels) as f64
+        })
+        .collect();
+    let to_loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean = |powers: &mut dyn Iterator<Item = f64>| {
+        let (sum, count) = powers.fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+        if count == 0 { None } else { Some(sum / count as f64) }
+    };
+    let absolute = mean(&mut powers.iter().cloned().filter(|power| to_loudness(*power) > -70.0))?;
+    let relative_gate = to_loudness(absolute) - 10.0;
+    mean(&mut powers.iter().cloned().filter(|power| to_loudness(*power) > -70.0 && to_loudness(*power) > relative_gate))
+        .map(to_loudness)
+}
+
+// brings the track to the target loudness, turning it down instead if the peaks would clip
+fn normalize(samples: &mut [f64], channels: usize, rate: f64) {
+    let loudness = match loudness(samples, channels, rate) {
+        Some(loudness) => loudness,
+        None => return,
+    };
+    let peak = samples.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+    let mut gain = 10f64.powf((TARGET_LOUDNESS - loudness) / 20.0);
+    if peak * gain > MAX_PEAK {
+        gain = MAX_PEAK / peak;
+    }
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+}
+
+// the rms of every frame, across all channels
+fn frame_levels(samples: &[f64], frame: usize) -> Vec<f64> {
+    samples.chunks(frame)
+        .map(|frame| (frame.iter().map(|sample| sample * sample).sum::<f64>() / frame.len() as f64).sqrt())
+        .collect()
+}
+
+// mutes the frames under the threshold, opening right away and closing slowly so words don't get cut
+fn gate(samples: &mut [f64], channels: usize, rate: f64) {
+    let frame = ((FRAME * rate) as usize * channels).max(channels);
+    let threshold = 10f64.powf(GATE_THRESHOLD / 20.0);
+    let release = (-1.0 / (GATE_RELEASE * rate)).exp();
+    let levels = frame_levels(samples, frame);
+    let mut gain = 0.0;
+    for (samples, level) in samples.chunks_mut(frame).zip(levels) {
+        let open = level > threshold;
+        for frame in samples.chunks_mut(channels) {
+            gain = if open { 1.0 } else { gain * release };
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+// turns down the frames that are close to the noise floor, like spectral subtraction but over the whole band at once.
+// the floor is the median of the frames well under the speech level, so a track that is all speech is left alone
+fn denoise(samples: &mut [f64], channels: usize, rate: f64) {
+    let frame = ((FRAME * rate) as usize * channels).max(channels);
+    let levels = frame_levels(samples, frame);
+    let mut sorted: Vec<f64> = levels.iter().cloned().filter(|level| *level > 0.0).collect();
+    if sorted.is_empty() {
+        return;
+    }
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    // discord only sends audio while someone talks, so most of the frames are speech
+    let speech = sorted[sorted.len() * 9 / 10];
+    let noise_limit = speech * 10f64.powf(NOISE_GAP / 20.0);
+    let noise: Vec<f64> = sorted.into_iter().take_while(|level| *level < noise_limit).collect();
+    if noise.is_empty() {
+        return;
+    }
+    let floor = noise[noise.len() / 2];
+    let min_gain = 10f64.powf(MAX_ATTENUATION / 20.0);
+    let gains: Vec<f64> = levels.iter()
+        .map(|level| (1.0 - (floor / level).powi(2)).max(0.0).sqrt().max(min_gain))
+        .collect();
+    // the gain slides from one frame's to the next so there are no clicks
+    for (index, samples) in samples.chunks_mut(frame).enumerate() {
+        let from = gains[index];
+        let to = gains.get(index + 1).cloned().unwrap_or(from);
+        let frames = (samples.len() / channels).max(1);
+        for (position, frame) in samples.chunks_mut(channels).enumerate() {
+            let gain = from + (to - from) * position as f64 / frames as f64;
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    // interleaved stereo, the same in both channels
+    fn sine(frequency: f64, amplitude: f64, seconds: f64) -> Vec<f64> {
+        (0..(seconds * RATE) as usize)
+            .flat_map(|index| {
+                let sample = amplitude * (2.0 * PI * frequency * index as f64 / RATE).sin();
+                vec![sample, sample]
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|sample| sample * sample).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn a_sine_measures_and_normalizes_as_expected() {
+        // a 997 Hz sine at -20 dBFS in both channels is -20 LUFS
+        let mut samples = sine(997.0, 0.1, 3.0);
+        let measured = loudness(&samples, 2, RATE).unwrap();
+        assert!((measured + 20.0).abs() < 0.01, "{}", measured);
+        normalize(&mut samples, 2, RATE);
+        let normalized = loudness(&samples, 2, RATE).unwrap();
+        assert!((normalized - TARGET_LOUDNESS).abs() < 0.01, "{}", normalized);
+        // too short to measure is left alone
+        assert_eq!(loudness(&sine(997.0, 0.1, 0.1), 2, RATE), None);
+    }
+
+    #[test]
+    fn the_high_pass_removes_dc_and_rumble() {
+        let settled = |samples: &[f64]| samples.len() / 2;
+        let mut dc = vec![0.5; 2 * RATE as usize];
+        Biquad::high_pass(HIGH_PASS_FREQUENCY, FRAC_1_SQRT_2, RATE).run(&mut dc, 2);
+        assert!(rms(&dc[settled(&dc)..]) < 1e-3);
+        let mut rumble = sine(20.0, 0.5, 2.0);
+        let before = rms(&rumble[settled(&rumble)..]);
+        Biquad::high_pass(HIGH_PASS_FREQUENCY, FRAC_1_SQRT_2, RATE).run(&mut rumble, 2);
+        assert!(rms(&rumble[settled(&rumble)..]) < before / 10.0);
+        // while voices go through
+        let mut voice = sine(300.0, 0.5, 2.0);
+        let before = rms(&voice[settled(&voice)..]);
+        Biquad::high_pass(HIGH_PASS_FREQUENCY, FRAC_1_SQRT_2, RATE).run(&mut voice, 2);
+        assert!(rms(&voice[settled(&voice)..]) > before * 0.95);
+    }
+
+    #[test]
+    fn the_gate_only_mutes_quiet_frames() {
+        let loud = sine(440.0, 0.5, 1.0);
+        let mut samples = loud.clone();
+        gate(&mut samples, 2, RATE);
+        assert_eq!(samples, loud);
+        // -60 dBFS is under the threshold, it fades out over the release
+        let mut samples = [loud.clone(), sine(440.0, 0.001, 1.0)].concat();
+        gate(&mut samples, 2, RATE);
+        assert_eq!(samples[..loud.len()], loud[..]);
+        assert!(rms(&samples[samples.len() - 2000..]) < 1e-6);
+    }
+
+    #[test]
+    fn denoise_leaves_speech_alone() {
+        // nothing well under the speech level, so there is no noise to take out
+        let speech: Vec<f64> = [sine(300.0, 0.5, 0.5), sine(500.0, 0.2, 0.5), sine(200.0, 0.3, 0.5)].concat();
+        let mut samples = speech.clone();
+        denoise(&mut samples, 2, RATE);
+        assert_eq!(samples, speech);
+        // a hiss between words is turned down, never by more than the limit, and the words aren't
+        let hiss = sine(5000.0, 0.002, 0.2);
+        let mut samples = [speech.clone(), hiss.clone(), speech.clone()].concat();
+        denoise(&mut samples, 2, RATE);
+        let words = rms(&samples[..speech.len()]) / rms(&speech);
+        assert!(words > 0.99, "{}", words);
+        let quiet = rms(&samples[speech.len() + 4000..speech.len() + hiss.len() - 4000]) / rms(&hiss);
+        let min_gain = 10f64.powf(MAX_ATTENUATION / 20.0);
+        assert!(quiet < 0.5 && quiet >= min_gain * 0.99, "{}", quiet);
+    }
+}
@@ -0,0 +1,263 @@
+use std::env;
+use serde_json::{Map, Value};
+use serenity::{
+    Result as SerenityResult,
+    builder::CreateApplicationCommand,
+    client::Context,
+    model::{
+        id::{CommandId, GuildId},
+        interactions::ApplicationCommand,
+    },
+};
+use crate::registry::COMMANDS;
+
+// where commands get registered: global commands take up to an hour to show up, guild ones are instant
+#[derive(Clone, Copy)]
+enum Scope {
+    Global,
+    Guild(GuildId),
+}
+
+impl Scope {
+    async fn get(self, ctx: &Context) -> SerenityResult<Vec<ApplicationCommand>> {
+        match self {
+            Scope::Global => ApplicationCommand::get_global_application_commands(ctx).await,
+            Scope::Guild(guild_id) => guild_id.get_application_commands(ctx).await,
+        }
+    }
+
+    async fn create(self, ctx: &Context, command: &CreateApplicationCommand) -> SerenityResult<ApplicationCommand> {
+        match self {
+            Scope::Global => ApplicationCommand::create_global_application_command(ctx, |c| replace(c, command)).await,
+            Scope::Guild(guild_id) => guild_id.create_application_command(ctx, |c| replace(c, command)).await,
+        }
+    }
+
+    async fn edit(self, ctx: &Context, command_id: CommandId, command: &CreateApplicationCommand) -> SerenityResult<ApplicationCommand> {
+        match self {
+            Scope::Global => ApplicationCommand::edit_global_application_command(ctx, command_id, |c| replace(c, command)).await,
+            Scope::Guild(guild_id) => guild_id.edit_application_command(ctx, command_id, |c| replace(c, command)).await,
+        }
+    }
+
+    async fn delete(self, ctx: &Context, command_id: CommandId) -> SerenityResult<()> {
+        match self {
+            Scope::Global => ApplicationCommand::delete_global_application_command(ctx, command_id).await,
+            Scope::Guild(guild_id) => guild_id.delete_application_command(ctx, command_id).await,
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Scope::Global => "globally".to_string(),
+            Scope::Guild(guild_id) => format!("in guild {}", guild_id),
+        }
+    }
+}
+
+// registers the commands in the dev guilds if there are any, globally otherwise
+pub async fn register(ctx: &Context) {
+    let commands = commands();
+    match dev_guilds() {
+        Some(guild_ids) => {
+            for guild_id in guild_ids {
+                sync(ctx, Scope::Guild(guild_id), &commands).await;
+            }
+        },
+        None => sync(ctx, Scope::Global, &commands).await,
+    }
+}
+
+// only touches the commands that changed, then reports what it did
+async fn sync(ctx: &Context, scope: Scope, commands: &[CreateApplicationCommand]) {
+    let registered = match scope.get(ctx).await {
+        Ok(registered) => registered,
+        Err(why) => {
+            eprintln!("Error fetching the commands registered {}: {:?}", scope.describe(), why);
+            return;
+        }
+    };
+    let mut created = Vec::new();
+    let mut updated = Vec::new();
+    let mut deleted = Vec::new();
+    let mut unchanged = 0;
+    for command in commands {
+        let name = command.0.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+        let result = match registered.iter().find(|registered| registered.name == name) {
+            Some(registered) if normalize(&registered_json(registered)) == normalize(&command_json(command)) => {
+                unchanged += 1;
+                Ok(())
+            },
+            Some(registered) => scope.edit(ctx, registered.id, command).await.map(|_| updated.push(name.clone())),
+            None => scope.create(ctx, command).await.map(|_| created.push(name.clone())),
+        };
+        if let Err(why) = result {
+            eprintln!("Error registering /{} {}: {:?}", name, scope.describe(), why);
+        }
+    }
+    for registered in registered.iter().filter(|registered| {
+        !commands.iter().any(|command| command.0.get("name").and_then(Value::as_str) == Some(&registered.name[..]))
+    }) {
+        match scope.delete(ctx, registered.id).await {
+            Ok(()) => deleted.push(registered.name.clone()),
+            Err(why) => eprintln!("Error deleting /{} {}: {:?}", registered.name, scope.describe(), why),
+        }
+    }
+    println!("Registered the commands {}: created [{}], updated [{}], deleted [{}], {} unchanged",
+             scope.describe(), created.join(", "), updated.join(", "), deleted.join(", "), unchanged);
+}
+
+// the parts of a command that are worth comparing
+fn command_json(command: &CreateApplicationCommand) -> Value {
+    let mut map = Map::new();
+    for key in ["name", "description", "options"].iter() {
+        if let Some(value) = command.0.get(key) {
+            map.insert(key.to_string(), value.clone());
+        }
+    }
+    Value::Object(map)
+}
+
+fn registered_json(command: &ApplicationCommand) -> Value {
+    let mut map = Map::new();
+    map.insert("name".to_string(), Value::String(command.name.clone()));
+    map.insert("description".to_string(), Value::String(command.description.clone()));
+    map.insert("options".to_string(), serde_json::to_value(&command.options).unwrap_or(Value::Null));
+    Value::Object(map)
+}
+
+// discord leaves out the fields that have default values, so these are ignored on both sides
+fn normalize(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(map.iter()
+            .filter(|(_, value)| !matches!(value, Value::Null | Value::Bool(false)))
+            .filter(|(_, value)| value.as_array().is_none_or(|array| !array.is_empty()))
+            .map(|(key, value)| (key.clone(), normalize(value)))
+            .collect()),
+        Value::Array(array) => Value::Array(array.iter().map(normalize).collect()),
+        value => value.clone(),
+    }
+}
+
+fn replace<'a>(builder: &'a mut CreateApplicationCommand, command: &CreateApplicationCommand) -> &'a mut CreateApplicationCommand {
+    builder.0 = command.0.clone();
+    builder
+}
+
+// every slash command the bot answers to
+fn commands() -> Vec<CreateApplicationCommand> {
+    COMMANDS.iter().map(|command| {
+        let mut builder = CreateApplicationCommand::default();
+        builder.name(command.name)
+            .description(command.description);
+        (command.options)(&mut builder);
+        builder
+    }).collect()
+}
+
+// comma separated ids of the guilds to register the commands in, instead of globally
+fn dev_guilds() -> Option<Vec<GuildId>> {
+    env::var("DISCORD_DEV_GUILDS").ok().map(|guilds| guilds.split(',')
+        .map(|guild_id| GuildId(guild_id.trim().parse().expect("make sure the dev guild ids are valid!")))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+    use crate::registry;
+
+    fn name(command: &CreateApplicationCommand) -> &str {
+        command.0.get("name").and_then(Value::as_str).expect("every command has a name")
+    }
+
+    #[test]
+    fn every_command_is_registered_under_its_name() {
+        let registrations = commands();
+        assert_eq!(registrations.len(), COMMANDS.len());
+        for (command, registration) in COMMANDS.iter().zip(&registrations) {
+            assert_eq!(name(registration), command.name);
+        }
+    }
+
+    // dispatch finds the first command with a name, a second one would never run
+    #[test]
+    fn every_name_is_unique() {
+        let names: HashSet<&str> = COMMANDS.iter().map(|command| command.name).collect();
+        assert_eq!(names.len(), COMMANDS.len());
+        for command in COMMANDS {
+            assert_eq!(registry::find(command.name).map(|command| command.name), Some(command.name));
+        }
+    }
+
+    // the choices of every "command" option in a registration, found however deep it's nested
+    fn command_choices(value: &Value, found: &mut Vec<HashSet<String>>) {
+        match value {
+            Value::Object(map) => {
+                if map.get("name").and_then(Value::as_str) == Some("command") {
+                    if let Some(choices) = map.get("choices").and_then(Value::as_array) {
+                        found.push(choices.iter()
+                            .filter_map(|choice| choice.get("value").and_then(Value::as_str).map(String::from))
+                            .collect());
+                    }
+                }
+                map.values().for_each(|value| command_choices(value, found));
+            },
+            Value::Array(array) => array.iter().for_each(|value| command_choices(value, found)),
+            _ => {},
+        }
+    }
+
+    fn choices_of(name: &str) -> Vec<HashSet<String>> {
+        let registration = commands().into_iter()
+            .find(|registration| self::name(registration) == name)
+            .expect("the command is registered");
+        let mut found = Vec::new();
+        command_choices(&command_json(&registration), &mut found);
+        found
+    }
+
+    #[test]
+    fn every_restrictable_command_can_be_restricted() {
+        let restrictable: HashSet<String> = COMMANDS.iter()
+            .filter(|command| command.restrictable)
+            .map(|command| command.name.to_string())
+            .collect();
+        let choices = choices_of("permissions");
+        // allow, disallow, require and reset
+        assert_eq!(choices.len(), 4);
+        for choices in choices {
+            assert_eq!(choices, restrictable);
+        }
+    }
+
+    #[test]
+    fn every_audited_command_can_be_searched_for() {
+        let audited: HashSet<String> = COMMANDS.iter()
+            .filter(|command| command.audited)
+            .map(|command| command.name.to_string())
+            .collect();
+        assert_eq!(choices_of("audit"), vec![audited]);
+    }
+
+    // discord refuses the whole sync over a single description that's too long
+    #[test]
+    fn descriptions_fit_discord() {
+        fn check(value: &Value) {
+            match value {
+                Value::Object(map) => {
+                    if let Some(description) = map.get("description").and_then(Value::as_str) {
+                        assert!((1..=100).contains(&description.chars().count()), "{:?}", description);
+                    }
+                    map.values().for_each(check);
+                },
+                Value::Array(array) => array.iter().for_each(check),
+                _ => {},
+            }
+        }
+        for registration in commands() {
+            check(&command_json(&registration));
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    future::Future,
+    pin::Pin,
+};
+use serenity::{
+    builder::{CreateApplicationCommand, CreateApplicationCommandOption},
+    model::interactions::ApplicationCommandOptionType,
+};
+use crate::{
+    commands,
+    structs::*,
+    timeline::TimelineFormat,
+};
+
+pub type Handler = fn(Response) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// everything about a slash command, both registration and dispatch are built from this
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub options: fn(&mut CreateApplicationCommand) -> &mut CreateApplicationCommand,
+    pub handler: Handler,
+    pub restrictable: bool, // whether /permissions can restrict it
+    pub audited: bool, // whether it shows up in the audit log
+    pub audits_itself: bool, // whether it logs itself instead of being logged when dispatched, dump does to add whose audio it exported
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "dump",
+        description: "Dumps the contents of the audio buffer in chat.",
+        options: |a| {
+            a.create_option(|opt| {
+                opt.name("format")
+                    .description("[defaults to ogg] the kind of files to make.")
+                    .kind(ApplicationCommandOptionType::String);
+                for format in OutputFormat::ALL.iter() {
+                    opt.add_string_choice(format.name(), format.name());
+                }
+                opt
+            })
+            .create_option(|opt| {
+                opt.name("archive")
+                    .description("[defaults to false] sends a single zip with every file and a manifest.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("mix")
+                    .description("[defaults to false] adds a track with everyone mixed together, needs every pause kept whole.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("multitrack")
+                    .description("[defaults to false] one wav or flac, everyone on their own channel, every pause kept whole.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("timeline")
+                    .description("also sends who spoke when, as subtitles or json, needs every pause kept whole.")
+                    .kind(ApplicationCommandOptionType::String);
+                for format in TimelineFormat::ALL.iter() {
+                    opt.add_string_choice(format.name(), format.name());
+                }
+                opt
+            })
+            .create_option(|opt| {
+                opt.name("normalize")
+                    .description("[defaults to false] brings everyone to the same loudness (EBU R128).")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("high_pass")
+                    .description("[defaults to false] cuts the rumble under 80 Hz.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("gate")
+                    .description("[defaults to false] mutes everything quieter than -45 dBFS.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("denoise")
+                    .description("[defaults to false] turns down the background noise.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("pauses")
+                    .description("[defaults to true] will include pauses between instanses of speech from the user.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+            .create_option(|opt| {
+                opt.name("max_pause")
+                    .description("cuts every pause down to at most this many milliseconds.")
+                    .kind(ApplicationCommandOptionType::Integer)
+            })
+            .create_option(|opt| {
+                opt.name("bitrate")
+                    .description("[defaults to 96] bitrate of the files in kbps.")
+                    .kind(ApplicationCommandOptionType::Integer)
+            })
+            .create_option(|opt| {
+                opt.name("sample_rate")
+                    .description("[defaults to 48000] sample rate of the files in Hz.")
+                    .kind(ApplicationCommandOptionType::Integer)
+                    .add_int_choice("16000", 16000)
+                    .add_int_choice("24000", 24000)
+                    .add_int_choice("48000", 48000)
+            })
+            .create_option(|opt| {
+                opt.name("channels")
+                    .description("[defaults to stereo] whether the files are mono or stereo.")
+                    .kind(ApplicationCommandOptionType::Integer)
+                    .add_int_choice("mono", 1)
+                    .add_int_choice("stereo", 2)
+            })
+            /*
+            .create_interaction_option(|opt| {
+            opt.name("merge")
+                .description("[defaults to false] will merge all the users' audio into one single track.")
+                .kind(ApplicationCommandOptionType::Boolean)
+
+            })*/
+        },
+        handler: |response| Box::pin(commands::dump(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: true,
+    },
+    Command {
+        name: "cancel",
+        description: "Stops the dumps that are still encoding.",
+        options: |a| a,
+        handler: |response| Box::pin(commands::cancel(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "stats",
+        description: "Shows who talked how much, over the buffered audio or the whole call.",
+        options: |a| {
+            a.create_option(|opt| {
+                opt.name("over")
+                    .description("[defaults to buffer] the buffered audio, or everything since the bot joined.")
+                    .kind(ApplicationCommandOptionType::String)
+                    .add_string_choice("buffer", "buffer")
+                    .add_string_choice("session", "session")
+            })
+        },
+        handler: |response| Box::pin(commands::stats(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "live",
+        description: "Shows who is talking and how loud, for a while.",
+        options: |a| {
+            a.create_option(|opt| {
+                opt.name("seconds")
+                    .description("[defaults to 60] how long to keep showing it, at most 600.")
+                    .kind(ApplicationCommandOptionType::Integer)
+            })
+            .create_option(|opt| {
+                opt.name("stop")
+                    .description("stops the live meter that is running.")
+                    .kind(ApplicationCommandOptionType::Boolean)
+            })
+        },
+        handler: |response| Box::pin(commands::live(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "clear",
+        description: "Clears the audio buffer.",
+        options: |a| a,
+        handler: |response| Box::pin(commands::clear(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "join",
+        description: "Makes the bot join your voice channel.",
+        options: |a| a,
+        handler: |response| Box::pin(commands::join(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "leave",
+        description: "Makes the bot leave your voice channel.",
+        options: |a| a,
+        handler: |response| Box::pin(commands::leave(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "follow",
+        description: "Makes the bot follow you around.",
+        options: |a| a,
+        handler: |response| Box::pin(commands::follow(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "unfollow",
+        description: "Makes the bot stop following you.",
+        options: |a| a,
+        handler: |response| Box::pin(commands::unfollow(response)),
+        restrictable: true,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "permissions",
+        description: "Restricts who can use the bot's commands.",
+        options: |a| {
+            a.create_option(|opt| {
+                opt.name("allow")
+                    .description("Lets members with a role use a command.")
+                    .kind(ApplicationCommandOptionType::SubCommand)
+                    .create_sub_option(command_option)
+                    .create_sub_option(|sub| {
+                        sub.name("role")
+                            .description("The role to allow.")
+                            .kind(ApplicationCommandOptionType::Role)
+                            .required(true)
+                    })
+            })
+            .create_option(|opt| {
+                opt.name("disallow")
+                    .description("Stops allowing a role to use a command.")
+                    .kind(ApplicationCommandOptionType::SubCommand)
+                    .create_sub_option(command_option)
+                    .create_sub_option(|sub| {
+                        sub.name("role")
+                            .description("The role to disallow.")
+                            .kind(ApplicationCommandOptionType::Role)
+                            .required(true)
+                    })
+            })
+            .create_option(|opt| {
+                opt.name("require")
+                    .description("Lets members with a discord permission use a command.")
+                    .kind(ApplicationCommandOptionType::SubCommand)
+                    .create_sub_option(command_option)
+                    .create_sub_option(|sub| {
+                        sub.name("permission")
+                            .description("The permission to require.")
+                            .kind(ApplicationCommandOptionType::String)
+                            .required(true);
+                        for (name, _) in PERMISSION_NAMES.iter() {
+                            sub.add_string_choice(name, name);
+                        }
+                        sub
+                    })
+            })
+            .create_option(|opt| {
+                opt.name("reset")
+                    .description("Lets everyone use a command again.")
+                    .kind(ApplicationCommandOptionType::SubCommand)
+                    .create_sub_option(command_option)
+            })
+            .create_option(|opt| {
+                opt.name("list")
+                    .description("Shows who can use which command.")
+                    .kind(ApplicationCommandOptionType::SubCommand)
+            })
+        },
+        handler: |response| Box::pin(commands::permissions(response)),
+        restrictable: false,
+        audited: true,
+        audits_itself: false,
+    },
+    Command {
+        name: "audit",
+        description: "Shows who used the bot's commands.",
+        options: |a| {
+            a.create_option(|opt| {
+                opt.name("user")
+                    .description("Only shows the commands used by this user.")
+                    .kind(ApplicationCommandOptionType::User)
+            })
+            .create_option(|opt| {
+                opt.name("command")
+                    .description("Only shows this command.")
+                    .kind(ApplicationCommandOptionType::String);
+                for command in COMMANDS.iter().filter(|command| command.audited) {
+                    opt.add_string_choice(command.name, command.name);
+                }
+                opt
+            })
+            .create_option(|opt| {
+                opt.name("limit")
+                    .description("[defaults to 10] how many entries to show, at most 25.")
+                    .kind(ApplicationCommandOptionType::Integer)
+            })
+        },
+        handler: |response| Box::pin(commands::audit(response)),
+        restrictable: false,
+        audited: false,
+        audits_itself: false,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+// the command a /permissions subcommand applies to
+fn command_option(opt: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    opt.name("command")
+        .description("The command to restrict.")
+        .kind(ApplicationCommandOptionType::String)
+        .required(true);
+    for command in COMMANDS.iter().filter(|command| command.restrictable) {
+        opt.add_string_choice(command.name, command.name);
+    }
+    opt
+}
@@ -0,0 +1,77 @@
+use std::{
+    env,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use serenity::{
+    client::bridge::gateway::ShardManager,
+    prelude::TypeMap,
+};
+use songbird::Songbird;
+use tokio::{
+    signal,
+    sync::{Mutex, RwLock},
+    time::timeout,
+};
+use crate::{
+    snapshot,
+    structs::*,
+};
+
+// resolves on ctrl+c, or on SIGTERM where there is one
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+}
+
+// stops taking commands, waits for the running ones (dumps included), then leaves every call
+pub async fn shutdown(data: Arc<RwLock<TypeMap>>, shard_manager: Arc<Mutex<ShardManager>>, songbird: Arc<Songbird>) {
+    println!("Shutting down...");
+    let data_read = data.read().await;
+    let shutdown_flag = data_read.get::<ShutdownFlag>().expect("Typemap incomplete").clone();
+    shutdown_flag.0.store(true, Ordering::SeqCst);
+    // live meters would otherwise keep the shutdown waiting for minutes
+    let live_meters = data_read.get::<LiveMeters>().expect("Typemap incomplete").clone();
+    for (_, running) in live_meters.lock().await.drain() {
+        let _ = running.send(true);
+    }
+    let _running = match timeout(shutdown_timeout(), shutdown_flag.1.write()).await {
+        Ok(guard) => Some(guard),
+        Err(_) => {
+            eprintln!("Some commands didn't finish in time, shutting down anyway");
+            None
+        }
+    };
+
+    let lobbies_lock = data_read.get::<Lobbies>().expect("Typemap incomplete").clone();
+    let lobbies = lobbies_lock.read().await;
+    if snapshot::enabled() {
+        snapshot::save_all(&lobbies).await;
+    }
+    let join_flag = data_read.get::<JoinFlag>().expect("Typemap incomplete").clone();
+    for guild_id in lobbies.keys() {
+        // so that the disconnect isn't mistaken for someone else's
+        join_flag.lock().await.insert(*guild_id);
+        if let Err(why) = songbird.remove(*guild_id).await {
+            eprintln!("Error leaving the call in {}: {:?}", guild_id, why);
+        }
+    }
+
+    shard_manager.lock().await.shutdown_all().await;
+}
+
+fn shutdown_timeout() -> Duration {
+    match env::var("DISCORD_SHUTDOWN_TIMEOUT") {
+        Ok(secs) => Duration::from_secs(secs.parse().expect("make sure the shutdown timeout is valid!")),
+        Err(_) => Duration::from_secs(30)
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    env,
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use serenity::model::prelude::{GuildId, UserId};
+use crate::structs::*;
+
+// snapshots are only taken when there is a directory to put them in
+pub fn enabled() -> bool {
+    snapshot_dir().is_some()
+}
+
+// writes every lobby's buffers, by user, to <dir>/<guild id>.snapshot
+pub async fn save_all(lobbies: &HashMap<GuildId, Lobby>) {
+    let dir = match snapshot_dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+    if let Err(why) = fs::create_dir_all(&dir) {
+        eprintln!("Error creating the snapshot directory: {:?}", why);
+        return;
+    }
+    for (guild_id, lobby) in lobbies.iter() {
+        let buffers = lobby.0.lock().await;
+        let ssrc_map = lobby.1.lock().await;
+        let restored = lobby.2.lock().await;
+        // ssrcs don't outlive the call, so the buffers go back to their users instead.
+        // the ones nobody claimed yet are kept too
+        let by_user: Vec<(UserId, &Buffer)> = buffers.iter()
+            .filter_map(|(ssrc, buffer)| Some((*ssrc_map.get(ssrc)?, buffer)))
+            .chain(restored.iter().map(|(user_id, buffer)| (*user_id, buffer)))
+            .collect();
+        match fs::write(snapshot_path(&dir, *guild_id), to_bytes(SystemTime::now(), &by_user)) {
+            Ok(()) => println!("Saved a snapshot of the buffers of {}", guild_id),
+            Err(why) => eprintln!("Error saving the snapshot of {}: {:?}", guild_id, why),
+        }
+    }
+}
+
+// a snapshot is deleted once it's read, so it's only ever restored once
+pub fn restore(guild_id: GuildId) -> Option<HashMap<UserId, Buffer>> {
+    let path = snapshot_path(&snapshot_dir()?, guild_id);
+    let bytes = fs::read(&path).ok()?;
+    let _ = fs::remove_file(&path);
+    let (saved_at, mut buffers) = match parse(&bytes) {
+        Some(restored) => restored,
+        None => {
+            eprintln!("Error restoring the snapshot of {}: the file is corrupted", guild_id);
+            return None;
+        }
+    };
+    resume(&mut buffers, SystemTime::now().duration_since(saved_at).unwrap_or_default());
+    println!("Restored the snapshot of {}", guild_id);
+    Some(buffers)
+}
+
+// nobody was heard while the bot was down, and nobody is until they talk again
+fn resume(buffers: &mut HashMap<UserId, Buffer>, downtime: Duration) {
+    for buffer in buffers.values_mut() {
+        buffer.push_padding(downtime);
+        buffer.push_silence();
+    }
+}
+
+// when it was saved, in milliseconds since the epoch, then each user's buffer
+fn to_bytes(saved_at: SystemTime, buffers: &[(UserId, &Buffer)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let saved_at = saved_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    bytes.extend_from_slice(&saved_at.to_be_bytes());
+    bytes.extend_from_slice(&(buffers.len() as u32).to_be_bytes());
+    for (user_id, buffer) in buffers {
+        let buffer_bytes = buffer.to_bytes();
+        bytes.extend_from_slice(&user_id.0.to_be_bytes());
+        bytes.extend_from_slice(&(buffer_bytes.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&buffer_bytes);
+    }
+    bytes
+}
+
+fn parse(bytes: &[u8]) -> Option<(SystemTime, HashMap<UserId, Buffer>)> {
+    let mut buffers = HashMap::new();
+    let saved_at = u64::from_be_bytes(bytes.get(0..8)?.try_into().unwrap());
+    let saved_at = UNIX_EPOCH.checked_add(Duration::from_millis(saved_at))?;
+    let count = u32::from_be_bytes(bytes.get(8..12)?.try_into().unwrap());
+    let mut at = 12;
+    for _ in 0..count {
+        let user_id = u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().unwrap());
+        let len = u64::from_be_bytes(bytes.get(at + 8..at + 16)?.try_into().unwrap()) as usize;
+        at += 16;
+        buffers.insert(UserId(user_id), Buffer::from_bytes(bytes.get(at..at.checked_add(len)?)?)?);
+        at += len;
+    }
+    // anything after the last buffer means the count was wrong
+    if at != bytes.len() {
+        return None;
+    }
+    Some((saved_at, buffers))
+}
+
+fn snapshot_path(dir: &str, guild_id: GuildId) -> PathBuf {
+    PathBuf::from(dir).join(format!("{}.snapshot", guild_id))
+}
+
+fn snapshot_dir() -> Option<String> {
+    env::var("DISCORD_SNAPSHOT_DIR").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(audio: &[i16]) -> Buffer {
+        let mut buffer = Buffer::new(AudioFormat::DECODED, 16);
+        buffer.push_audio(&audio.to_vec());
+        buffer.push_silence();
+        buffer.push_silence_end();
+        buffer
+    }
+
+    fn snapshot(buffers: &[(UserId, &Buffer)]) -> Vec<u8> {
+        to_bytes(UNIX_EPOCH + Duration::from_millis(1_600_000_000_123), buffers)
+    }
+
+    #[test]
+    fn a_snapshot_round_trips() {
+        let (first, second) = (buffer(&[1, 2, 3]), buffer(&[4]));
+        let (saved_at, buffers) = parse(&snapshot(&[(UserId(10), &first), (UserId(20), &second)]))
+            .expect("a saved snapshot is always valid");
+        assert_eq!(saved_at, UNIX_EPOCH + Duration::from_millis(1_600_000_000_123));
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(buffers[&UserId(10)].to_bytes(), first.to_bytes());
+        assert_eq!(buffers[&UserId(20)].to_bytes(), second.to_bytes());
+        let (_, buffers) = parse(&to_bytes(SystemTime::now(), &[])).unwrap();
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn truncated_or_corrupt_snapshots_are_refused() {
+        let bytes = snapshot(&[(UserId(10), &buffer(&[1, 2, 3])), (UserId(20), &buffer(&[4]))]);
+        for len in 0..bytes.len() {
+            assert!(parse(&bytes[..len]).is_none(), "{} of {} bytes", len, bytes.len());
+        }
+        assert!(parse(&[&bytes[..], &[0]].concat()).is_none());
+        let corrupt = |at: usize, with: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[at..at + with.len()].copy_from_slice(with);
+            parse(&bytes)
+        };
+        // one buffer too many, a length past the end, and a broken buffer
+        assert!(corrupt(8, &3u32.to_be_bytes()).is_none());
+        assert!(corrupt(20, &u64::MAX.to_be_bytes()).is_none());
+        assert!(corrupt(28 + 22, &[7]).is_none());
+    }
+
+    #[test]
+    fn the_downtime_is_a_pause() {
+        let mut buffers = HashMap::new();
+        buffers.insert(UserId(10), buffer(&[1, 2]));
+        resume(&mut buffers, Duration::from_secs(2));
+        let buffer = &buffers[&UserId(10)];
+        let (samples, segments) = buffer.pop_uncompressed(None, Duration::MAX);
+        assert!(samples.len() >= 2 + AudioFormat::DECODED.samples(Duration::from_secs(2)));
+        assert_eq!(segments, vec![0..2]);
+        // the pause only ends once they talk again
+        let (samples, _) = buffer.pop_uncompressed(Some(Duration::ZERO), Duration::MAX);
+        assert_eq!(samples, vec![1, 2]);
+    }
+}
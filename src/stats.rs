@@ -0,0 +1,197 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
+use serenity::model::prelude::UserId;
+
+// pauses shorter than this don't end a turn, speaking updates flicker on every breath
+const TURN_GAP: Duration = Duration::from_secs(1);
+
+pub struct SpeakerStats {
+    pub user_id: UserId,
+    pub talk_time: Duration,
+    pub turns: usize,
+    pub longest_turn: Duration,
+    pub interruptions: usize, // turns started while someone else was talking
+}
+
+// the speech of every speaker over the same stretch of time, busiest speaker first
+pub fn compute(speech: &[(UserId, Vec<Range<Instant>>)]) -> Vec<SpeakerStats> {
+    // a user can have more than one ssrc
+    let mut by_user: HashMap<UserId, Vec<Range<Instant>>> = HashMap::new();
+    for (user_id, segments) in speech {
+        by_user.entry(*user_id).or_default().extend(segments.iter().cloned());
+    }
+    for segments in by_user.values_mut() {
+        segments.sort_by_key(|segment| segment.start);
+    }
+    let turns: HashMap<UserId, Vec<Range<Instant>>> = by_user.iter()
+        .map(|(user_id, segments)| (*user_id, turns(segments)))
+        .collect();
+    let mut stats: Vec<SpeakerStats> = by_user.iter().map(|(user_id, segments)| {
+        let own_turns = &turns[user_id];
+        let interruptions = own_turns.iter().filter(|turn| {
+            turns.iter()
+                .filter(|(other_id, _)| *other_id != user_id)
+                .any(|(_, other_turns)| other_turns.iter().any(|other| other.start < turn.start && turn.start < other.end))
+        }).count();
+        SpeakerStats {
+            user_id: *user_id,
+            talk_time: segments.iter().map(|segment| segment.end - segment.start).sum(),
+            turns: own_turns.len(),
+            longest_turn: own_turns.iter().map(|turn| turn.end - turn.start).max().unwrap_or_default(),
+            interruptions,
+        }
+    }).collect();
+    stats.sort_by_key(|speaker| Reverse(speaker.talk_time));
+    stats
+}
+
+// counted as the audio and speaking updates come in, so it covers the whole call and not only
+// what is still buffered. a turn is interrupting when someone else is talking as it starts
+pub struct Session {
+    pub started: Instant,
+    speakers: HashMap<UserId, SessionSpeaker>,
+}
+
+#[derive(Default)]
+struct SessionSpeaker {
+    talk_time: Duration,
+    turns: usize,
+    longest_turn: Duration,
+    interruptions: usize,
+    talking_since: Option<Instant>, // the stretch of speech going on
+    turn: Option<Range<Instant>>, // the latest turn, which goes on if they talk again soon enough
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            speakers: HashMap::new(),
+        }
+    }
+
+    // audio came in, which starts a stretch of speech unless one is going on
+    pub fn heard(&mut self, user_id: UserId, now: Instant) {
+        let someone_else_talking = self.speakers.iter()
+            .any(|(other_id, other)| *other_id != user_id && other.talking_since.is_some());
+        let speaker = self.speakers.entry(user_id).or_default();
+        if speaker.talking_since.is_some() {
+            return;
+        }
+        speaker.talking_since = Some(now);
+        match &speaker.turn {
+            Some(turn) if now.saturating_duration_since(turn.end) < TURN_GAP => {},
+            _ => {
+                speaker.turns += 1;
+                if someone_else_talking {
+                    speaker.interruptions += 1;
+                }
+                speaker.turn = Some(now..now);
+            },
+        }
+    }
+
+    // the user went quiet
+    pub fn stopped(&mut self, user_id: UserId, now: Instant) {
+        if let Some(speaker) = self.speakers.get_mut(&user_id) {
+            if let Some(talking_since) = speaker.talking_since.take() {
+                speaker.talk_time += now.saturating_duration_since(talking_since);
+                if let Some(turn) = &mut speaker.turn {
+                    turn.end = now;
+                    speaker.longest_turn = speaker.longest_turn.max(turn.end - turn.start);
+                }
+            }
+        }
+    }
+
+    // what is still going on counts up to now, busiest speaker first
+    pub fn stats(&self, now: Instant) -> Vec<SpeakerStats> {
+        let mut stats: Vec<SpeakerStats> = self.speakers.iter().map(|(user_id, speaker)| {
+            let talking = speaker.talking_since.map_or(Duration::ZERO, |talking_since| now.saturating_duration_since(talking_since));
+            let turn = match (&speaker.turn, speaker.talking_since) {
+                (Some(turn), Some(_)) => now.saturating_duration_since(turn.start),
+                (Some(turn), None) => turn.end - turn.start,
+                (None, _) => Duration::ZERO,
+            };
+            SpeakerStats {
+                user_id: *user_id,
+                talk_time: speaker.talk_time + talking,
+                turns: speaker.turns,
+                longest_turn: speaker.longest_turn.max(turn),
+                interruptions: speaker.interruptions,
+            }
+        }).collect();
+        stats.sort_by_key(|speaker| Reverse(speaker.talk_time));
+        stats
+    }
+}
+
+// joins the stretches of speech that are only a breath apart
+fn turns(speech: &[Range<Instant>]) -> Vec<Range<Instant>> {
+    let mut turns: Vec<Range<Instant>> = Vec::new();
+    for segment in speech {
+        match turns.last_mut() {
+            Some(turn) if segment.start.saturating_duration_since(turn.end) < TURN_GAP => turn.end = turn.end.max(segment.end),
+            _ => turns.push(segment.clone()),
+        }
+    }
+    turns
+}
+
+// 1m 05s
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn a_session_counts_turns_and_interruptions() {
+        let mut session = Session::new();
+        let start = session.started;
+        // the first one talks twice with a breath in between, which is one turn
+        session.heard(UserId(1), at(start, 0));
+        session.stopped(UserId(1), at(start, 2000));
+        session.heard(UserId(1), at(start, 2500));
+        // the second one starts while they are still talking
+        session.heard(UserId(2), at(start, 3000));
+        session.heard(UserId(2), at(start, 3100));
+        session.stopped(UserId(1), at(start, 4000));
+        session.stopped(UserId(2), at(start, 3500));
+        // and the first one again, long after
+        session.heard(UserId(1), at(start, 10000));
+        let stats = session.stats(at(start, 11000));
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].user_id, UserId(1));
+        assert_eq!(stats[0].talk_time, Duration::from_millis(2000 + 1500 + 1000));
+        assert_eq!(stats[0].turns, 2);
+        assert_eq!(stats[0].longest_turn, Duration::from_millis(4000));
+        assert_eq!(stats[0].interruptions, 0);
+        assert_eq!(stats[1].user_id, UserId(2));
+        assert_eq!(stats[1].talk_time, Duration::from_millis(500));
+        assert_eq!(stats[1].turns, 1);
+        assert_eq!(stats[1].interruptions, 1);
+    }
+
+    #[test]
+    fn quiet_users_are_left_out() {
+        let mut session = Session::new();
+        session.stopped(UserId(1), Instant::now());
+        assert!(session.stats(Instant::now()).is_empty());
+    }
+}
@@ -1,28 +1,44 @@
 use std::{
     collections::HashMap,
     sync::Arc,
-    env
+    env,
+    fs
 };
 use tokio::{
     sync::{
         Mutex,
-        RwLock
+        OwnedSemaphorePermit,
+        RwLock,
+        Semaphore,
+        watch
     },
 };
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use serenity::{
+    async_trait,
     Result as SerenityResult,
     model::{
-        guild::Guild,
-        prelude::{GuildId, UserId},
-        interactions::{Interaction, InteractionResponseType, InteractionApplicationCommandCallbackDataFlags, ApplicationCommandInteractionData},
+        permissions::Permissions,
+        prelude::{ChannelId, GuildId, MessageId, RoleId, UserId},
+        channel::Message,
+        interactions::{Interaction, InteractionResponseType, InteractionApplicationCommandCallbackDataFlags, ApplicationCommandInteractionDataOption},
     },
     client::Context,
-    prelude::TypeMapKey,
+    prelude::{TypeMap, TypeMapKey},
 };
+use songbird::{
+    Call,
+    CoreEvent,
+};
+use crate::{registry, stats::Session};
 use std::collections::HashSet;
+use std::sync::atomic::AtomicBool;
+use std::convert::TryInto;
+use std::ops::Range;
 use std::time::{Duration, Instant};
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum AudioState {
     Timestamp(Instant),
     Padding(Duration),
@@ -30,110 +46,326 @@ pub enum AudioState {
     Null
 }
 
+// how the samples in a buffer are laid out, channels are interleaved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub rate: u32,
+    pub channels: u16,
+}
+
+impl AudioFormat {
+    // what songbird decodes voice packets to
+    pub const DECODED: AudioFormat = AudioFormat { rate: 48000, channels: 2 };
+
+    // rounded down to whole frames so the channels don't get swapped
+    pub fn samples(&self, duration: Duration) -> usize {
+        ((duration.as_secs_f64() * self.rate as f64) as usize).saturating_mul(self.channels as usize)
+    }
+
+    pub fn duration(&self, samples: usize) -> Duration {
+        Duration::from_secs_f64(samples as f64 / (self.rate as f64 * self.channels as f64))
+    }
+}
+
+// audio taken out of a buffer to be exported
+pub struct Track {
+    pub name: String,
+    pub user_id: Option<UserId>,
+    pub ssrc: Option<u32>,
+    pub format: AudioFormat,
+    pub samples: Vec<i16>,
+    pub segments: Vec<Range<usize>>, // where in the samples the speaker talks
+    pub sources: Vec<UserId>, // whose audio is in it, everyone that went into a mix
+}
+
+impl Track {
+    pub fn duration(&self) -> Duration {
+        self.format.duration(self.samples.len())
+    }
+}
+
+// a ring of the latest `size` entries: `pos` is where the next entry goes, so once the ring
+// has wrapped it is also the oldest entry, and reading from `pos` to the end then from the
+// start up to `pos` always goes from oldest to newest (unwritten slots are Null).
+// `silence_pos`, when set, points at the Timestamp of the silence that is still going on;
+// it is cleared when the silence ends or when the ring overwrites that slot.
 pub struct Buffer {
     buf: Vec<AudioState>,
     pos: usize,
     silence_pos: Option<usize>,
     size: usize,
+    format: AudioFormat,
+    level: f64, // rms of the latest packet, from 0 to 1
+    last_audio: Option<Instant>,
 }
 
 impl Buffer {
-    pub fn new() -> Self {
-        let size = buffer_size();
+    pub fn new(format: AudioFormat, size: usize) -> Self {
         Self {
             buf: vec![AudioState::Null; size],
             pos: 0,
             silence_pos: None,
             size,
+            format,
+            level: 0.0,
+            last_audio: None,
         }
     }
 
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
     pub fn push_silence_end(&mut self) {
-        if let Some(pos) = self.silence_pos {
+        if let Some(pos) = self.silence_pos.take() {
             if let AudioState::Timestamp(time) = self.buf[pos] {
-                    self.buf[pos] = AudioState::Padding(time.elapsed());
+                self.buf[pos] = AudioState::Padding(time.elapsed());
             }
         }
     }
 
     pub fn push_audio(&mut self, val: &Vec<i16>) {
         for bytes in val {
-            self.buf[self.pos] = AudioState::Audio(*bytes);
-            self.pos = if self.pos < self.size - 1 { self.pos + 1 } else { 0 };
+            self.write(AudioState::Audio(*bytes));
+        }
+        if !val.is_empty() {
+            let squares: f64 = val.iter().map(|sample| (*sample as f64 / 32768.0).powi(2)).sum();
+            self.level = (squares / val.len() as f64).sqrt();
+            self.last_audio = Some(Instant::now());
         }
     }
 
-    pub fn push_silence(&mut self) {
-        self.buf[self.pos] = AudioState::Timestamp(Instant::now());
-        self.silence_pos = Some(self.pos);
-        self.pos = if self.pos < self.size - 1 { self.pos + 1 } else { 0 };
+    // how loud the user is right now, none once no audio came for a moment
+    pub fn level(&self) -> Option<f64> {
+        self.last_audio
+            .filter(|last_audio| last_audio.elapsed() < Duration::from_millis(500))
+            .map(|_| self.level)
     }
 
-    pub fn pop_compressed(&self) -> Vec<i16> {
-        let start = if self.pos < self.size - 1 { self.pos } else { 0 };
-        let to_unwrap: Vec<AudioState> = [&self.buf[start..], &self.buf[..start]].concat();
-        to_unwrap.iter().filter_map(|elem| {
-            match elem {
-                AudioState::Audio(audio) => Some(*audio),
-                _ => None
+    // a silence whose length is already known, such as the time the bot was down
+    pub fn push_padding(&mut self, duration: Duration) {
+        self.push_silence_end();
+        self.write(AudioState::Padding(duration));
+    }
+
+    // puts what another buffer holds after what this one holds, as if it had been pushed here.
+    // a silence still going on in this one ends now, one going on in the other keeps going
+    pub fn append(&mut self, newer: &Buffer) {
+        self.push_silence_end();
+        // where the other's pending silence is among its entries, oldest first
+        let silence_index = newer.silence_pos.map(|pos| (pos + newer.size - newer.pos) % newer.size);
+        for (index, elem) in newer.entries().enumerate() {
+            if let AudioState::Null = elem {
+                continue
             }
-        }).collect()
+            let pos = self.pos;
+            self.write(elem.clone());
+            if silence_index == Some(index) {
+                self.silence_pos = Some(pos);
+            }
+        }
+        if newer.last_audio.is_some() {
+            self.level = newer.level;
+            self.last_audio = newer.last_audio;
+        }
+    }
+
+    // a silence that is already going on keeps its start
+    pub fn push_silence(&mut self) {
+        if self.silence_pos.is_none() {
+            let pos = self.pos;
+            self.write(AudioState::Timestamp(Instant::now()));
+            self.silence_pos = Some(pos);
+        }
     }
 
-    pub fn pop_uncompressed(&self) -> Vec<i16> {
-        let start = if self.pos < self.size - 1 { self.pos } else { 0 };
-        let mut audio_state_buffer: Vec<AudioState> = [&self.buf[start..], &self.buf[..start]].concat();
-        audio_state_buffer.reverse();
+    fn write(&mut self, elem: AudioState) {
+        if self.silence_pos == Some(self.pos) {
+            self.silence_pos = None;
+        }
+        self.buf[self.pos] = elem;
+        self.pos = (self.pos + 1) % self.size;
+    }
+
+    // oldest to newest
+    fn entries(&self) -> impl DoubleEndedIterator<Item = &AudioState> {
+        self.buf[self.pos..].iter().chain(self.buf[..self.pos].iter())
+    }
+
+    // only the speech, along with where each stretch of it is
+    pub fn pop_compressed(&self) -> (Vec<i16>, Vec<Range<usize>>) {
+        self.pop_uncompressed(Some(Duration::ZERO), Duration::MAX)
+    }
+
+    // each gap is cut to max_gap, and once the silences add up to more than max_total the older audio is left out.
+    // the ranges are the stretches of speech in the samples, split by every silence even when it was cut to nothing
+    pub fn pop_uncompressed(&self, max_gap: Option<Duration>, max_total: Duration) -> (Vec<i16>, Vec<Range<usize>>) {
         let now = Instant::now();
+        let max_total = self.format.samples(max_total);
         let mut silence_duration: usize = 0;
-        let mut output_chunks: Vec<Vec<i16>> = Vec::new();
-        for elem in audio_state_buffer {
+        // both are built newest first and turned around at the end
+        let mut samples: Vec<i16> = Vec::new();
+        let mut segments: Vec<Range<usize>> = Vec::new();
+        let mut speech_end = None;
+        for elem in self.entries().rev() {
+            let gap = match elem {
+                AudioState::Audio(audio) => {
+                    speech_end.get_or_insert(samples.len());
+                    samples.push(*audio);
+                    continue
+                },
+                AudioState::Padding(duration) => *duration,
+                AudioState::Timestamp(time) => now.duration_since(*time),
+                AudioState::Null => continue,
+            };
+            if let Some(speech_end) = speech_end.take() {
+                segments.push(speech_end..samples.len());
+            }
+            let padding = self.format.samples(max_gap.map_or(gap, |max_gap| gap.min(max_gap)));
+            silence_duration += padding;
+            if silence_duration > max_total {
+                break
+            }
+            samples.resize(samples.len() + padding, 0);
+        }
+        if let Some(speech_end) = speech_end {
+            segments.push(speech_end..samples.len());
+        }
+        let length = samples.len();
+        samples.reverse();
+        let segments = segments.into_iter().rev()
+            .map(|segment| length - segment.end..length - segment.start)
+            .collect();
+        (samples, segments)
+    }
+
+    // when each stretch of speech in the buffer happened, oldest first
+    pub fn speech(&self) -> Vec<Range<Instant>> {
+        let now = Instant::now();
+        // how far back from now the walk is, audio is counted in samples to add up exactly
+        let mut gaps = Duration::ZERO;
+        let mut audio = 0;
+        let mut speech_end = None;
+        let mut speech = Vec::new();
+        for elem in self.entries().rev() {
+            let gap = match elem {
+                AudioState::Audio(_) => {
+                    speech_end.get_or_insert(gaps + self.format.duration(audio));
+                    audio += 1;
+                    continue
+                },
+                AudioState::Padding(duration) => *duration,
+                AudioState::Timestamp(time) => now.duration_since(*time),
+                AudioState::Null => continue,
+            };
+            if let Some(speech_end) = speech_end.take() {
+                speech.push((gaps + self.format.duration(audio), speech_end));
+            }
+            gaps += gap;
+        }
+        if let Some(speech_end) = speech_end {
+            speech.push((gaps + self.format.duration(audio), speech_end));
+        }
+        speech.into_iter().rev()
+            .filter_map(|(start, end)| Some(now.checked_sub(start)?..now.checked_sub(end)?))
+            .collect()
+    }
+
+    // pending silences become paddings that end now, since instants can't outlive the process
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(22 + self.size * 3);
+        bytes.extend_from_slice(&self.format.rate.to_be_bytes());
+        bytes.extend_from_slice(&self.format.channels.to_be_bytes());
+        bytes.extend_from_slice(&(self.size as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.pos as u64).to_be_bytes());
+        for elem in &self.buf {
             match elem {
-                AudioState::Audio(audio) => output_chunks.push(vec![audio]),
+                AudioState::Null => bytes.push(0),
+                AudioState::Audio(audio) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&audio.to_be_bytes());
+                },
                 AudioState::Padding(duration) => {
-                    let padding = (duration.as_secs_f64() * 96000.0) as usize;
-                    silence_duration += padding;
-                    if silence_duration > (120 * 96000) {
-                        break
-                    } else {
-                        output_chunks.push(vec![0; padding])
-                    }
+                    bytes.push(2);
+                    bytes.extend_from_slice(&duration.as_secs_f64().to_be_bytes());
                 },
                 AudioState::Timestamp(time) => {
-                    let duration = now.duration_since(time);
-                    let padding = (duration.as_secs_f64() * 96000.0) as usize;
-                    silence_duration += padding;
-                    if silence_duration > (120 * 96000) {
-                        break
-                    } else {
-                        output_chunks.push(vec![0; padding])
-                    }
+                    bytes.push(2);
+                    bytes.extend_from_slice(&time.elapsed().as_secs_f64().to_be_bytes());
                 },
-                AudioState::Null => {},
             }
         }
-        output_chunks.reverse();
-        output_chunks.concat()
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let read_u64 = |at: usize| bytes.get(at..at + 8)
+            .map(|b| u64::from_be_bytes(b.try_into().unwrap()));
+        let format = AudioFormat {
+            rate: u32::from_be_bytes(bytes.get(0..4)?.try_into().unwrap()),
+            channels: u16::from_be_bytes(bytes.get(4..6)?.try_into().unwrap()),
+        };
+        let size = read_u64(6)? as usize;
+        let pos = read_u64(14)? as usize;
+        let mut buf = Vec::new();
+        let mut at = 22;
+        while at < bytes.len() {
+            match bytes[at] {
+                0 => {
+                    buf.push(AudioState::Null);
+                    at += 1;
+                },
+                1 => {
+                    let audio = bytes.get(at + 1..at + 3)?;
+                    buf.push(AudioState::Audio(i16::from_be_bytes(audio.try_into().unwrap())));
+                    at += 3;
+                },
+                2 => {
+                    let secs = bytes.get(at + 1..at + 9)?;
+                    buf.push(AudioState::Padding(Duration::try_from_secs_f64(f64::from_be_bytes(secs.try_into().unwrap())).ok()?));
+                    at += 9;
+                },
+                _ => return None,
+            }
+        }
+        if format.rate == 0 || format.channels == 0 || size == 0 || buf.len() != size || pos >= size {
+            return None;
+        }
+        // every silence was saved as a padding, none of them is still going on
+        Some(Self {
+            buf,
+            pos,
+            silence_pos: None,
+            size,
+            format,
+            level: 0.0,
+            last_audio: None,
+        })
     }
 
 }
 
+// audio buffers by ssrc, the users the ssrcs belong to, the buffers restored from a snapshot
+// by user, until that user shows up again with a new ssrc, and the stats of the whole call
+pub type Lobby = Arc<(Mutex<HashMap<u32, Buffer>>, Mutex<HashMap<u32, UserId>>, Mutex<HashMap<UserId, Buffer>>, Mutex<Session>)>;
+
 pub struct Receiver {
-    pub lobby: Arc<(Mutex<HashMap<u32, Buffer>>, Mutex<HashMap<u32, UserId>>)>,
+    pub lobby: Lobby,
+    pub buffer_size: usize, // of every buffer it makes
 }
 
 impl Receiver {
-    pub fn new(lobby: Arc<(Mutex<HashMap<u32, Buffer>>, Mutex<HashMap<u32, UserId>>)>) -> Self {
+    pub fn new(lobby: Lobby) -> Self {
         // You can manage state here, such as a buffer of audio packet bytes so
         // you can later store them in intervals.
-        Self { lobby }
+        Self { lobby, buffer_size: buffer_size() }
     }
 }
 
 pub struct Lobbies; // void struct used to generate a typemap that holds all active lobbies
 
 impl TypeMapKey for Lobbies {
-    type Value = Arc<RwLock<HashMap<GuildId, Arc<(Mutex<HashMap<u32, Buffer>>, Mutex<HashMap<u32, UserId>>)>>>>; // a game is held within a lobby. the text channel id is the lobby's unique code
+    type Value = Arc<RwLock<HashMap<GuildId, Lobby>>>; // a game is held within a lobby. the text channel id is the lobby's unique code
 }
 
 pub struct JoinFlag;
@@ -142,12 +374,255 @@ impl TypeMapKey for JoinFlag {
     type Value = Arc<Mutex<HashSet<GuildId>>>;
 }
 
+pub struct ShutdownFlag;
+
+impl TypeMapKey for ShutdownFlag {
+    type Value = Arc<(AtomicBool, RwLock<()>)>; // set once the bot is shutting down, and held by every running command
+}
+
+// limits how many encoders run at once across every guild
+pub struct EncodingQueue {
+    slots: Arc<Semaphore>,
+    turns: Mutex<HashMap<GuildId, Arc<Mutex<()>>>>,
+}
+
+impl EncodingQueue {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(concurrency)),
+            turns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // only one job per guild waits for a slot at a time, and slots are handed out in order,
+    // so a guild dumping a crowded call can't starve the others
+    pub async fn acquire(&self, guild_id: GuildId) -> OwnedSemaphorePermit {
+        let turn = self.turns.lock().await.entry(guild_id).or_default().clone();
+        let _turn = turn.lock().await;
+        self.slots.clone().acquire_owned().await.expect("the encoding queue is never closed")
+    }
+}
+
+impl TypeMapKey for EncodingQueue {
+    type Value = Arc<EncodingQueue>;
+}
+
+pub struct LiveMeters;
+
+impl TypeMapKey for LiveMeters {
+    type Value = Arc<Mutex<HashMap<GuildId, watch::Sender<bool>>>>; // set to true to stop the guild's live meter
+}
+
+pub struct DumpCancels;
+
+impl TypeMapKey for DumpCancels {
+    type Value = Arc<Mutex<HashMap<u64, RunningDump>>>; // by a number each dump gets when it starts
+}
+
+// reacting with it on the progress reply of a dump cancels that dump
+pub const CANCEL_REACTION: char = '❌';
+
+// a dump that is still encoding
+pub struct RunningDump {
+    pub guild_id: GuildId,
+    pub user_id: UserId, // who started it, the only one whose reaction cancels it
+    pub message_id: Option<MessageId>, // the progress reply, which has the cancel reaction
+    pub cancel: watch::Sender<bool>, // set to true to cancel it
+}
+
 pub struct FollowFlag;
 
 impl TypeMapKey for FollowFlag {
     type Value = Arc<Mutex<HashMap<GuildId, UserId>>>;
 }
 
+// what the bot does when someone else moves or disconnects it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejoinPolicy {
+    Always, // go back to the channel it was in
+    Never, // stay wherever it was put
+    Following, // go back only if it is following someone, to wherever they are
+}
+
+impl TypeMapKey for RejoinPolicy {
+    type Value = RejoinPolicy; // read from the environment once, on startup
+}
+
+// what a dump does when its options don't say otherwise
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DumpDefaults {
+    pub format: OutputFormat,
+    pub max_pause: Option<Duration>, // a longer pause is cut short when dumping with pauses
+    pub max_silence: Duration, // silence a dump can hold in total, older audio is left out past this
+    pub bitrate: u64, // kbps
+    pub sample_rate: Option<u32>, // the buffer's when unset
+    pub channels: Option<u16>, // the buffer's when unset
+}
+
+impl Default for DumpDefaults {
+    fn default() -> Self {
+        DumpDefaults {
+            format: OutputFormat::Ogg,
+            max_pause: None,
+            max_silence: Duration::from_secs(120),
+            bitrate: 96,
+            sample_rate: None,
+            channels: None,
+        }
+    }
+}
+
+impl TypeMapKey for DumpDefaults {
+    type Value = DumpDefaults; // read from the environment once, on startup
+}
+
+pub struct AuditLog;
+
+impl TypeMapKey for AuditLog {
+    type Value = String; // the path of the audit log, read from the environment once, on startup
+}
+
+// the kinds of files dumps are encoded to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Ogg, // opus in an ogg container
+    Mp3,
+    Wav,
+    Flac,
+    M4a, // aac in an mp4 container
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 5] = [OutputFormat::Ogg, OutputFormat::Mp3, OutputFormat::Wav, OutputFormat::Flac, OutputFormat::M4a];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match &name.to_lowercase()[..] {
+            "ogg" | "opus" => Some(OutputFormat::Ogg),
+            "mp3" => Some(OutputFormat::Mp3),
+            "wav" => Some(OutputFormat::Wav),
+            "flac" => Some(OutputFormat::Flac),
+            "m4a" | "aac" => Some(OutputFormat::M4a),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        self.extension()
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Ogg => "ogg",
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Wav => "wav",
+            OutputFormat::Flac => "flac",
+            OutputFormat::M4a => "m4a",
+        }
+    }
+
+    // ffmpeg's -f
+    pub fn muxer(self) -> &'static str {
+        match self {
+            OutputFormat::Ogg => "ogg",
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Wav => "wav",
+            OutputFormat::Flac => "flac",
+            OutputFormat::M4a => "ipod",
+        }
+    }
+
+    // ffmpeg's -c:a
+    pub fn codec(self) -> &'static str {
+        match self {
+            OutputFormat::Ogg => "libopus",
+            OutputFormat::Mp3 => "libmp3lame",
+            OutputFormat::Wav => "pcm_s16le",
+            OutputFormat::Flac => "flac",
+            OutputFormat::M4a => "aac",
+        }
+    }
+
+    // lossless formats ignore the bitrate
+    pub fn lossless(self) -> bool {
+        matches!(self, OutputFormat::Wav | OutputFormat::Flac)
+    }
+
+    // anything else ffmpeg needs to write the format to a pipe
+    pub fn extra_args(self) -> &'static [&'static str] {
+        match self {
+            // mp4 normally goes back to the start of the file to write its index
+            OutputFormat::M4a => &["-movflags", "+frag_keyframe+empty_moov"],
+            _ => &[],
+        }
+    }
+}
+
+// panics on an unknown format, main calls it on startup so that happens right away
+pub fn output_format() -> OutputFormat {
+    match env::var("DISCORD_OUTPUT_FORMAT") {
+        Ok(name) => OutputFormat::from_name(&name)
+            .expect("DISCORD_OUTPUT_FORMAT must be one of ogg, mp3, wav, flac or m4a"),
+        Err(_) => OutputFormat::Ogg
+    }
+}
+
+// who can use a command in a guild: anyone with one of the roles, or with all of the permissions
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PermissionRule {
+    pub roles: HashSet<RoleId>,
+    pub permissions: u64,
+}
+
+impl PermissionRule {
+    pub fn allows(&self, roles: &[RoleId], permissions: Permissions) -> bool {
+        let required = Permissions::from_bits_truncate(self.permissions);
+        roles.iter().any(|role| self.roles.contains(role))
+            || (!required.is_empty() && permissions.contains(required))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roles.is_empty() && self.permissions == 0
+    }
+}
+
+pub struct PermissionRules; // commands without a rule can be used by anyone
+
+impl TypeMapKey for PermissionRules {
+    type Value = Arc<RwLock<HashMap<GuildId, HashMap<String, PermissionRule>>>>;
+}
+
+// the discord permissions a command can be restricted to, by the name used in /permissions
+pub const PERMISSION_NAMES: [(&str, Permissions); 6] = [
+    ("administrator", Permissions::ADMINISTRATOR),
+    ("manage_server", Permissions::MANAGE_GUILD),
+    ("manage_channels", Permissions::MANAGE_CHANNELS),
+    ("manage_messages", Permissions::MANAGE_MESSAGES),
+    ("move_members", Permissions::MOVE_MEMBERS),
+    ("mute_members", Permissions::MUTE_MEMBERS),
+];
+
+pub fn load_permission_rules() -> HashMap<GuildId, HashMap<String, PermissionRule>> {
+    match fs::read_to_string(permissions_file()) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .expect("make sure the permissions file is valid!"),
+        Err(_) => HashMap::default()
+    }
+}
+
+pub fn save_permission_rules(rules: &HashMap<GuildId, HashMap<String, PermissionRule>>) {
+    let json = serde_json::to_string_pretty(rules).expect("permission rules are always serializable");
+    if let Err(why) = fs::write(permissions_file(), json) {
+        eprintln!("Error saving permissions: {:?}", why);
+    }
+}
+
+fn permissions_file() -> String {
+    match env::var("DISCORD_PERMISSIONS_FILE") {
+        Ok(path) => path,
+        Err(_) => "permissions.json".to_string()
+    }
+}
+
 fn buffer_size () -> usize {
     match env::var("DISCORD_BUFFER_SIZE") {
         Ok(custom_size) => custom_size.parse::<usize>()
@@ -156,54 +631,375 @@ fn buffer_size () -> usize {
     }
 }
 
+// how many ffmpeg processes can run at once, defaults to the number of cpus
+pub fn encoders() -> usize {
+    match env::var("DISCORD_ENCODERS") {
+        Ok(encoders) => encoders.parse::<usize>().ok().filter(|encoders| *encoders > 0)
+            .expect("make sure the number of encoders is valid!"),
+        Err(_) => std::thread::available_parallelism().map_or(2, |cpus| cpus.get())
+    }
+}
+
+// a command's name and options, whether it came from an interaction or a message
+pub struct CommandData {
+    pub name: String,
+    pub options: Vec<CommandOption>,
+}
+
+// subcommands have no value, and their own options
+pub struct CommandOption {
+    pub name: String,
+    pub value: Option<Value>,
+    pub options: Vec<CommandOption>,
+}
+
+impl CommandOption {
+    fn from_interaction(option: &ApplicationCommandInteractionDataOption) -> Self {
+        Self {
+            name: option.name.clone(),
+            value: option.value.clone(),
+            options: option.options.iter().map(CommandOption::from_interaction).collect(),
+        }
+    }
+}
+
+enum Source {
+    Interaction(Box<Interaction>),
+    Message(Box<Message>, Mutex<Option<MessageId>>), // the command, and the reply that gets edited
+}
+
 pub struct Response {
-    interaction: Interaction,
+    source: Source,
+    server: CachedServer,
+    data: CommandData,
+    channel_id: ChannelId,
+    member: UserId,
+    member_roles: Vec<RoleId>,
+    member_permissions: Permissions,
+}
+
+// the guild a command runs in: who is in which voice channel, the members, the bot's call and the shared data.
+// kept apart from serenity's context so commands can run against something else
+#[async_trait]
+pub trait Server: Send + Sync {
+    fn guild_id(&self) -> GuildId;
+
+    fn typemap(&self) -> &Arc<RwLock<TypeMap>>;
+
+    async fn bot_id(&self) -> UserId;
+
+    async fn voice_channel(&self, user_id: UserId) -> Option<ChannelId>;
+
+    // everyone in the voice channel, the bot included
+    async fn voice_members(&self, channel_id: ChannelId) -> Vec<UserId>;
+
+    async fn member_name(&self, user_id: UserId) -> Option<String>;
+
+    async fn join_voice(&self, channel_id: ChannelId) -> Result<(), ()>;
+
+    // records the call the bot is in into the lobby
+    async fn listen(&self, lobby: Lobby);
+
+    // none when the bot is not in a call
+    async fn leave_voice(&self) -> Option<Result<(), ()>>;
+
+    async fn play(&self, path: &str);
+
+    async fn say(&self, channel_id: ChannelId, message_content: &str);
+}
+
+// the server as the gateway and songbird see it
+#[derive(Clone)]
+pub struct CachedServer {
+    ctx: Context,
+    guild_id: GuildId,
+}
+
+impl CachedServer {
+    pub fn new(ctx: Context, guild_id: GuildId) -> Self {
+        Self { ctx, guild_id }
+    }
+
+    async fn call(&self) -> Option<Arc<Mutex<Call>>> {
+        songbird::get(&self.ctx).await
+            .expect("Songbird Voice client placed in at initialisation.")
+            .get(self.guild_id)
+    }
+}
+
+#[async_trait]
+impl Server for CachedServer {
+    fn guild_id(&self) -> GuildId {
+        self.guild_id
+    }
+
+    fn typemap(&self) -> &Arc<RwLock<TypeMap>> {
+        &self.ctx.data
+    }
+
+    async fn bot_id(&self) -> UserId {
+        self.ctx.cache.current_user_id().await
+    }
+
+    async fn voice_channel(&self, user_id: UserId) -> Option<ChannelId> {
+        self.ctx.cache.guild_field(self.guild_id, |guild| {
+            guild.voice_states.get(&user_id).and_then(|vs| vs.channel_id)
+        }).await.flatten()
+    }
+
+    async fn voice_members(&self, channel_id: ChannelId) -> Vec<UserId> {
+        self.ctx.cache.guild_field(self.guild_id, |guild| {
+            guild.voice_states.values()
+                .filter(|vs| vs.channel_id == Some(channel_id))
+                .map(|vs| vs.user_id)
+                .collect()
+        }).await.unwrap_or_default()
+    }
+
+    async fn member_name(&self, user_id: UserId) -> Option<String> {
+        self.ctx.cache.member(self.guild_id, user_id).await.map(|member| member.user.name)
+    }
+
+    async fn join_voice(&self, channel_id: ChannelId) -> Result<(), ()> {
+        let manager = songbird::get(&self.ctx).await
+            .expect("Songbird Voice client placed in at initialisation.").clone();
+        let (_, conn_result) = manager.join(self.guild_id, channel_id).await;
+        conn_result.map_err(|_| ())
+    }
+
+    async fn listen(&self, lobby: Lobby) {
+        // NOTE: this skips listening for the actual connection result.
+        if let Some(call) = self.call().await {
+            let mut handler = call.lock().await;
+            for event in [CoreEvent::VoicePacket, CoreEvent::SpeakingStateUpdate, CoreEvent::SpeakingUpdate, CoreEvent::ClientDisconnect] {
+                handler.add_global_event(event.into(), Receiver::new(lobby.clone()));
+            }
+        }
+    }
+
+    async fn leave_voice(&self) -> Option<Result<(), ()>> {
+        let call = self.call().await?;
+        let left = call.lock().await.leave().await;
+        Some(left.map_err(|_| ()))
+    }
+
+    async fn play(&self, path: &str) {
+        match songbird::ffmpeg(path).await {
+            Ok(source) => if let Some(call) = self.call().await {
+                call.lock().await.play_source(source);
+            },
+            Err(why) => eprintln!("Error playing {}: {:?}", path, why),
+        }
+    }
+
+    async fn say(&self, channel_id: ChannelId, message_content: &str) {
+        check(channel_id.say(&self.ctx, message_content).await);
+    }
+}
+
+// everything commands need from whoever invoked them, so they don't depend on where the command came from
+#[async_trait]
+pub trait Reply: Server {
+    fn data(&self) -> &CommandData;
+
+    fn channel_id(&self) -> ChannelId;
+
+    fn member(&self) -> UserId;
+
+    fn member_roles(&self) -> &[RoleId];
+
+    fn member_permissions(&self) -> Permissions;
+
+    async fn edit(&self, message_content: &str);
+
+    async fn follow_up(&self, message_content: &str);
+
+    async fn follow_up_files(&self, files: &[(Vec<u8>, String)]) -> Result<(), ()>;
+
+    // puts the cancel reaction on the reply that gets edited, and says which message that is
+    async fn add_cancel_reaction(&self) -> Option<MessageId>;
 }
 
 impl Response {
     pub async fn new(ctx: &Context, interaction: Interaction) -> Result<Response, ()> {
-        if let Ok(_) = interaction.create_interaction_response(ctx, |response| {
+        let (data, guild_id, channel_id, member) = match (&interaction.data, interaction.guild_id, interaction.channel_id, &interaction.member) {
+            (Some(data), Some(guild_id), Some(channel_id), Some(member)) => (data, guild_id, channel_id, member),
+            _ => return Err(()),
+        };
+        let data = CommandData {
+            name: data.name.clone(),
+            options: data.options.iter().map(CommandOption::from_interaction).collect(),
+        };
+        let member_id = member.user.id;
+        let member_roles = member.roles.clone();
+        // the member's permissions in the channel the command was used in
+        let member_permissions = member.permissions.unwrap_or_else(Permissions::empty);
+        if interaction.create_interaction_response(ctx, |response| {
             response.interaction_response_data(|m| {
                 m.flags(InteractionApplicationCommandCallbackDataFlags::EPHEMERAL)
             })
                 .kind(InteractionResponseType::DeferredChannelMessageWithSource) // WARN si comporta come DeferredChannelMessageWithSource
-        }).await {
+        }).await.is_ok() {
             Ok(Response{
-                interaction,
+                source: Source::Interaction(Box::new(interaction)),
+                server: CachedServer::new(ctx.clone(), guild_id),
+                data,
+                channel_id,
+                member: member_id,
+                member_roles,
+                member_permissions,
             })
         } else {
             Err(())
         }
     }
 
-    pub fn data(&self) -> &Option<ApplicationCommandInteractionData> {
-        &self.interaction.data
+    // only messages that start with the prefix are commands, e.g. `!dump pauses:false`
+    pub async fn from_message(ctx: &Context, message: Message) -> Result<Response, ()> {
+        let prefix = command_prefix();
+        if prefix.is_empty() || message.author.bot {
+            return Err(());
+        }
+        let (data, guild_id) = match (message.content.strip_prefix(&prefix[..]).and_then(parse_command), message.guild_id) {
+            (Some(data), Some(guild_id)) => (data, guild_id),
+            _ => return Err(()),
+        };
+        // before anything is looked up, any message can start with the prefix
+        if registry::find(&data.name).is_none() {
+            return Err(());
+        }
+        let guild = ctx.cache.guild(guild_id).await.ok_or(())?;
+        let member_permissions = guild.member_permissions(ctx, message.author.id).await.map_err(|_| ())?;
+        Ok(Response {
+            server: CachedServer::new(ctx.clone(), guild_id),
+            data,
+            channel_id: message.channel_id,
+            member: message.author.id,
+            member_roles: message.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default(),
+            member_permissions,
+            source: Source::Message(Box::new(message), Mutex::new(None)),
+        })
     }
 
-    pub async fn guild(&self, ctx: &Context) -> (Guild, GuildId) {
-        let guild_id = self.interaction.guild_id.unwrap();
-        let guild = ctx.cache.guild(guild_id).await.unwrap();
-        (guild, guild_id)
+    async fn send_files_embed_on_channel (&self, files: &[(Vec<u8>, String)]) -> Result<(), ()> {
+        let files_with_references = files.iter()
+            .map(|(audio, name)| (&audio[..], &name[..])).collect::<Vec<_>>();
+        match self.channel_id.send_message(&self.server.ctx, |m| m.add_files(files_with_references)).await {
+            Ok(_) => Ok(()),
+            Err(why) => {
+                check::<()>(Err(why));
+                Err(())
+            }
+        }
     }
+}
 
-    pub fn member(&self) -> UserId {
-        self.interaction.member.as_ref().unwrap().user.id
+#[async_trait]
+impl Server for Response {
+    fn guild_id(&self) -> GuildId {
+        self.server.guild_id()
     }
 
-    pub async fn edit(&self, ctx: &Context, message_content: &str) {
-        check(self.interaction.edit_original_interaction_response(ctx, |m| {
+    fn typemap(&self) -> &Arc<RwLock<TypeMap>> {
+        self.server.typemap()
+    }
+
+    async fn bot_id(&self) -> UserId {
+        self.server.bot_id().await
+    }
+
+    async fn voice_channel(&self, user_id: UserId) -> Option<ChannelId> {
+        self.server.voice_channel(user_id).await
+    }
+
+    async fn voice_members(&self, channel_id: ChannelId) -> Vec<UserId> {
+        self.server.voice_members(channel_id).await
+    }
+
+    async fn member_name(&self, user_id: UserId) -> Option<String> {
+        self.server.member_name(user_id).await
+    }
+
+    async fn join_voice(&self, channel_id: ChannelId) -> Result<(), ()> {
+        self.server.join_voice(channel_id).await
+    }
+
+    async fn listen(&self, lobby: Lobby) {
+        self.server.listen(lobby).await
+    }
+
+    async fn leave_voice(&self) -> Option<Result<(), ()>> {
+        self.server.leave_voice().await
+    }
+
+    async fn play(&self, path: &str) {
+        self.server.play(path).await
+    }
+
+    async fn say(&self, channel_id: ChannelId, message_content: &str) {
+        self.server.say(channel_id, message_content).await
+    }
+}
+
+#[async_trait]
+impl Reply for Response {
+    fn data(&self) -> &CommandData {
+        &self.data
+    }
+
+    fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    fn member(&self) -> UserId {
+        self.member
+    }
+
+    fn member_roles(&self) -> &[RoleId] {
+        &self.member_roles
+    }
+
+    fn member_permissions(&self) -> Permissions {
+        self.member_permissions
+    }
+
+    // interactions have a single reply to edit, messages get a reply that is edited from then on
+    async fn edit(&self, message_content: &str) {
+        let ctx = &self.server.ctx;
+        match &self.source {
+            Source::Interaction(interaction) => check(interaction.edit_original_interaction_response(ctx, |m| {
                 m.create_embed(|e| e.description(message_content))
-        }).await)
+            }).await),
+            Source::Message(message, reply) => {
+                let mut reply = reply.lock().await;
+                match *reply {
+                    Some(reply_id) => check(message.channel_id.edit_message(ctx, reply_id, |m| {
+                        m.embed(|e| e.description(message_content))
+                    }).await),
+                    None => match message.channel_id.send_message(ctx, |m| {
+                        m.reference_message(&**message)
+                            .embed(|e| e.description(message_content))
+                    }).await {
+                        Ok(sent) => *reply = Some(sent.id),
+                        Err(why) => check::<()>(Err(why)),
+                    },
+                }
+            }
+        }
     }
 
-    pub async fn follow_up(&self, ctx: &Context, message_content: &str) {
-        check(self.interaction.create_followup_message(ctx, |m| {
-            m.content(message_content)
-                //.embed(|e| e.description(message_content))
-        }).await)
+    async fn follow_up(&self, message_content: &str) {
+        let ctx = &self.server.ctx;
+        match &self.source {
+            Source::Interaction(interaction) => check(interaction.create_followup_message(ctx, |m| {
+                m.content(message_content)
+                    //.embed(|e| e.description(message_content))
+            }).await),
+            Source::Message(message, _) => check(message.channel_id.say(ctx, message_content).await),
+        }
     }
 
-    pub async fn follow_up_files(&self, ctx: &Context, files: &Vec<(Vec<u8>, String)>) {
+    async fn follow_up_files(&self, files: &[(Vec<u8>, String)]) -> Result<(), ()> {
         /*
             let files_with_references = files.iter()
             .map(|(audio, name)| (&audio[..], &name[..])).collect::<Vec<_>>();
@@ -213,18 +1009,233 @@ impl Response {
                 .embed(|m| m.description(message_content))
             }).await)
          */
-        self.send_files_embed_on_channel(ctx, files).await
+        self.send_files_embed_on_channel(files).await
     }
 
-    async fn send_files_embed_on_channel (&self, ctx: &Context, files: &Vec<(Vec<u8>, String)>) {
-        let files_with_references = files.iter()
-            .map(|(audio, name)| (&audio[..], &name[..])).collect::<Vec<_>>();
-        check(self.interaction.channel_id.unwrap().send_message(ctx, |m| m.add_files(files_with_references)).await);
+    async fn add_cancel_reaction(&self) -> Option<MessageId> {
+        let ctx = &self.server.ctx;
+        let message_id = match &self.source {
+            Source::Interaction(interaction) => match interaction.get_interaction_response(ctx).await {
+                Ok(reply) => reply.id,
+                Err(why) => {
+                    check::<()>(Err(why));
+                    return None;
+                },
+            },
+            Source::Message(_, reply) => (*reply.lock().await)?,
+        };
+        check(self.channel_id.create_reaction(ctx, message_id, CANCEL_REACTION).await);
+        Some(message_id)
     }
 }
 
-fn check<T>(result: SerenityResult<T>) {
+// `name word key:value` becomes the command name, a subcommand and one of its options
+fn parse_command(content: &str) -> Option<CommandData> {
+    let mut words = content.split_whitespace();
+    let name = words.next()?.to_lowercase();
+    let mut options: Vec<CommandOption> = Vec::new();
+    for word in words {
+        match word.split_once(':') {
+            Some((key, value)) => {
+                let option = CommandOption {
+                    name: key.to_lowercase(),
+                    value: Some(parse_value(value)),
+                    options: Vec::new(),
+                };
+                // options after a subcommand belong to it
+                match options.last_mut() {
+                    Some(subcommand) if subcommand.value.is_none() => subcommand.options.push(option),
+                    _ => options.push(option),
+                }
+            },
+            None => options.push(CommandOption {
+                name: word.to_lowercase(),
+                value: None,
+                options: Vec::new(),
+            }),
+        }
+    }
+    Some(CommandData { name, options })
+}
+
+// values look like the ones interactions send: mentions become plain ids
+fn parse_value(value: &str) -> Value {
+    if let Ok(flag) = value.parse::<bool>() {
+        return Value::Bool(flag);
+    }
+    if let Ok(number) = value.parse::<i64>() {
+        return Value::from(number);
+    }
+    let id = value.strip_prefix('<')
+        .and_then(|value| value.strip_suffix('>'))
+        .map(|value| value.trim_start_matches(['@', '!', '&', '#']))
+        .filter(|id| id.parse::<u64>().is_ok());
+    Value::String(id.unwrap_or(value).to_string())
+}
+
+fn command_prefix() -> String {
+    match env::var("DISCORD_PREFIX") {
+        Ok(prefix) => prefix,
+        Err(_) => "!".to_string()
+    }
+}
+
+pub fn check<T>(result: SerenityResult<T>) {
     if let Err(why) = result {
         eprintln!("Error sending response: {:?}", why);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, so every run walks the same cases
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+
+    // pushes audio, silences and silence ends at random, keeping the audio that was pushed in order
+    fn fill(buffer: &mut Buffer, rng: &mut Rng, steps: usize, pushed: &mut Vec<i16>) {
+        for _ in 0..steps {
+            match rng.below(4) {
+                0 => buffer.push_silence(),
+                1 => buffer.push_silence_end(),
+                _ => {
+                    let audio: Vec<i16> = (0..rng.below(4)).map(|_| rng.next() as i16).collect();
+                    pushed.extend(&audio);
+                    buffer.push_audio(&audio);
+                },
+            }
+            if let Some(pos) = buffer.silence_pos {
+                assert!(matches!(buffer.buf[pos], AudioState::Timestamp(_)), "silence_pos points at {:?}", buffer.buf[pos]);
+            }
+        }
+    }
+
+    fn audio(buffer: &Buffer) -> Vec<i16> {
+        buffer.entries()
+            .filter_map(|elem| match elem {
+                AudioState::Audio(audio) => Some(*audio),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn entries_go_from_oldest_to_newest() {
+        for size in 1..10 {
+            let mut buffer = Buffer::new(AudioFormat::DECODED, size);
+            // every pos comes up at least twice, before and after the ring wraps
+            for written in 1..=3 * size as i16 {
+                buffer.push_audio(&vec![written]);
+                let expected: Vec<i16> = (1.max(written - size as i16 + 1)..=written).collect();
+                assert_eq!(buffer.entries().count(), size);
+                assert_eq!(audio(&buffer), expected, "size {} pos {}", size, buffer.pos);
+            }
+        }
+    }
+
+    #[test]
+    fn overwriting_a_silence_forgets_it() {
+        for size in 1..10 {
+            let mut buffer = Buffer::new(AudioFormat::DECODED, size);
+            buffer.push_audio(&vec![1; size / 2]);
+            buffer.push_silence();
+            buffer.push_audio(&vec![2; size]);
+            assert_eq!(buffer.silence_pos, None);
+            // ending it now must not turn the audio in its slot into a pause
+            buffer.push_silence_end();
+            assert_eq!(audio(&buffer), vec![2; size]);
+        }
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let mut buffer = Buffer::new(AudioFormat::DECODED, 1 + rng.below(16));
+            fill(&mut buffer, &mut rng, 100, &mut Vec::new());
+        }
+    }
+
+    #[test]
+    fn compressed_is_only_the_audio() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..200 {
+            let size = 1 + rng.below(32);
+            let mut buffer = Buffer::new(AudioFormat::DECODED, size);
+            let mut pushed = Vec::new();
+            let steps = rng.below(100);
+            fill(&mut buffer, &mut rng, steps, &mut pushed);
+            let (samples, segments) = buffer.pop_compressed();
+            // the newest audio that is still in the ring, nothing added in between
+            assert!(pushed.ends_with(&samples));
+            assert_eq!(samples, audio(&buffer));
+            assert_eq!(segments.iter().map(|segment| segment.len()).sum::<usize>(), samples.len());
+            assert!(segments.windows(2).all(|pair| pair[0].end <= pair[1].start));
+            assert!(segments.iter().all(|segment| !segment.is_empty()));
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        for _ in 0..200 {
+            let mut buffer = Buffer::new(AudioFormat { rate: 16000, channels: 1 }, 1 + rng.below(32));
+            let steps = rng.below(100);
+            fill(&mut buffer, &mut rng, steps, &mut Vec::new());
+            buffer.push_silence_end();
+            let bytes = buffer.to_bytes();
+            let restored = Buffer::from_bytes(&bytes).expect("a saved buffer is always valid");
+            assert_eq!(restored.format(), buffer.format());
+            assert_eq!((restored.pos, restored.size), (buffer.pos, buffer.size));
+            assert_eq!(restored.pop_uncompressed(None, Duration::MAX), buffer.pop_uncompressed(None, Duration::MAX));
+            assert_eq!(restored.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn a_pending_silence_is_saved_as_a_padding() {
+        let mut buffer = Buffer::new(AudioFormat::DECODED, 8);
+        buffer.push_audio(&vec![1, 2]);
+        buffer.push_silence();
+        let restored = Buffer::from_bytes(&buffer.to_bytes()).unwrap();
+        assert!(matches!(restored.buf[2], AudioState::Padding(_)));
+        assert_eq!(restored.silence_pos, None);
+        // so the next silence starts a new one instead of ending the saved one
+        let mut restored = restored;
+        restored.push_silence();
+        assert_eq!(restored.silence_pos, Some(3));
+    }
+
+    #[test]
+    fn truncated_or_corrupt_bytes_are_refused() {
+        let mut buffer = Buffer::new(AudioFormat::DECODED, 6);
+        buffer.push_audio(&vec![1, 2]);
+        buffer.push_silence();
+        buffer.push_silence_end();
+        buffer.push_audio(&vec![3]);
+        let bytes = buffer.to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Buffer::from_bytes(&bytes[..len]).is_none(), "{} of {} bytes", len, bytes.len());
+        }
+        assert!(Buffer::from_bytes(&[&bytes[..], &[0]].concat()).is_none());
+        let corrupt = |at: usize, with: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[at..at + with.len()].copy_from_slice(with);
+            Buffer::from_bytes(&bytes)
+        };
+        // an unknown entry, a pos outside the ring, no channels, and a negative pause
+        assert!(corrupt(22, &[7]).is_none());
+        assert!(corrupt(14, &6u64.to_be_bytes()).is_none());
+        assert!(corrupt(4, &0u16.to_be_bytes()).is_none());
+        assert!(corrupt(22 + 3 + 3 + 1, &(-1.0f64).to_be_bytes()).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+use serde::Serialize;
+use serenity::model::prelude::UserId;
+use crate::structs::Track;
+
+// the kinds of files a timeline of who spoke when can be written to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimelineFormat {
+    Vtt,
+    Srt,
+    Json,
+}
+
+impl TimelineFormat {
+    pub const ALL: [TimelineFormat; 3] = [TimelineFormat::Vtt, TimelineFormat::Srt, TimelineFormat::Json];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match &name.to_lowercase()[..] {
+            "vtt" | "webvtt" => Some(TimelineFormat::Vtt),
+            "srt" => Some(TimelineFormat::Srt),
+            "json" => Some(TimelineFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TimelineFormat::Vtt => "vtt",
+            TimelineFormat::Srt => "srt",
+            TimelineFormat::Json => "json",
+        }
+    }
+}
+
+// a stretch of time one speaker talked for
+#[derive(Serialize)]
+pub struct Segment {
+    pub speaker: String,
+    pub user_id: Option<UserId>,
+    #[serde(serialize_with = "seconds")]
+    pub start: Duration,
+    #[serde(serialize_with = "seconds")]
+    pub end: Duration,
+}
+
+// the tracks are lined up by their ends like the mix, so times are from the start of the longest one.
+// like the mix, that only holds when no pause was cut out of them
+pub fn segments(tracks: &[Track]) -> Vec<Segment> {
+    let length = tracks.iter().map(Track::duration).max().unwrap_or_default();
+    let mut segments: Vec<Segment> = tracks.iter().flat_map(|track| {
+        let offset = length - track.duration();
+        track.segments.iter().map(move |segment| Segment {
+            speaker: track.name.clone(),
+            user_id: track.user_id,
+            start: offset + track.format.duration(segment.start),
+            end: offset + track.format.duration(segment.end),
+        })
+    }).collect();
+    segments.sort_by_key(|segment| (segment.start, segment.end));
+    segments
+}
+
+pub fn render(segments: &[Segment], format: TimelineFormat) -> String {
+    match format {
+        TimelineFormat::Vtt => {
+            let mut vtt = "WEBVTT\n\n".to_string();
+            for segment in segments {
+                let speaker = escape(&caption(&segment.speaker));
+                vtt += &format!("{} --> {}\n<v {}>{}\n\n",
+                                timestamp(segment.start, '.'), timestamp(segment.end, '.'), speaker, speaker);
+            }
+            vtt
+        },
+        TimelineFormat::Srt => {
+            let mut srt = String::new();
+            for (index, segment) in segments.iter().enumerate() {
+                srt += &format!("{}\n{} --> {}\n{}\n\n",
+                                index + 1, timestamp(segment.start, ','), timestamp(segment.end, ','), caption(&segment.speaker));
+            }
+            srt
+        },
+        TimelineFormat::Json => serde_json::to_string_pretty(segments).expect("segments are always serializable"),
+    }
+}
+
+// an arrow in a cue's text would be read as the start of the next cue
+fn caption(speaker: &str) -> String {
+    let mut caption = speaker.to_string();
+    while caption.contains("-->") {
+        caption = caption.replace("-->", "");
+    }
+    caption
+}
+
+// vtt cue text is markup, so a name like <b> would be taken as a tag
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// hh:mm:ss.mmm, srt wants a comma before the milliseconds
+fn timestamp(time: Duration, separator: char) -> String {
+    let millis = time.as_millis();
+    format!("{:02}:{:02}:{:02}{}{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, separator, millis % 1000)
+}
+
+fn seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}